@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+
+use crate::{Args, fmt_size};
+use crate::tree::Tree;
+
+
+
+/// How --breakdown groups the files in the summary.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Breakdown {
+	/// By file extension
+	Ext,
+	/// By a coarse category: source, text, image, audio, video, archive, binary, data, other
+	Category,
+}
+
+const CATEGORIES: &[(&str, &[&str])] = &[
+	("source", &["rs", "c", "h", "cc", "cpp", "hpp", "cs", "go", "java", "kt", "py", "rb", "js", "mjs", "ts", "tsx", "jsx", "php", "swift",
+		"scala", "hs", "lua", "pl", "sh", "bash", "ps1", "bat", "sql", "zig", "vue", "css", "scss", "html", "htm"]),
+	("text", &["txt", "md", "rst", "org", "tex", "log", "adoc", "pdf", "doc", "docx", "odt", "rtf"]),
+	("data", &["json", "yaml", "yml", "toml", "xml", "csv", "tsv", "ini", "cfg", "conf", "lock", "db", "sqlite", "parquet"]),
+	("image", &["png", "jpg", "jpeg", "gif", "bmp", "svg", "webp", "ico", "tif", "tiff", "heic", "psd", "raw"]),
+	("audio", &["mp3", "wav", "flac", "ogg", "m4a", "aac", "opus", "wma"]),
+	("video", &["mp4", "mkv", "avi", "mov", "webm", "wmv", "flv", "m4v"]),
+	("archive", &["zip", "tar", "gz", "tgz", "bz2", "xz", "zst", "7z", "rar", "jar", "deb", "rpm", "iso", "dmg"]),
+	("binary", &["exe", "dll", "so", "dylib", "a", "lib", "o", "obj", "bin", "class", "pyc", "wasm", "rlib"]),
+];
+
+fn category(ext: &str) -> &'static str {
+	CATEGORIES.iter().find(|(_, exts)| exts.contains(&ext)).map_or("other", |(name, _)| name)
+}
+
+#[derive(Default)]
+struct Group {
+	count: u64,
+	size: u64,
+	largest: (u64, String),
+}
+
+fn collect(tree: &Tree, by: Breakdown, args: &Args) -> HashMap<String, Group> {
+	let mut groups: HashMap<String, Group> = HashMap::new();
+	// Files that couldn't be read have no size or type to count
+	for file in tree.files.iter().filter(|f| f.regex_matched && f.error.is_none()) {
+		let ext = file.path.extension().map(|e| e.to_string_lossy().to_lowercase()).unwrap_or_default();
+		let key = match by {
+			Breakdown::Ext if ext.is_empty() => "(none)".to_string(),
+			Breakdown::Ext => ext,
+			Breakdown::Category => category(&ext).to_string(),
+		};
+		let size = file.size(args.apparent_size);
+		let group = groups.entry(key).or_default();
+		group.count += 1;
+		group.size += size;
+		// Ties go to the first path so the example doesn't depend on the tree's layout
+		let path = file.path.strip_prefix(&tree.root().path).unwrap_or(&file.path).to_string_lossy().to_string();
+		if group.count == 1 || size > group.largest.0 || (size == group.largest.0 && path < group.largest.1) {
+			group.largest = (size, path);
+		}
+	}
+	groups
+}
+
+/// The --breakdown table for the summary: count, size, share of the matched size and the largest file of each group.
+pub fn breakdown_lines(tree: &Tree, by: Breakdown, args: &Args) -> Vec<String> {
+	let groups = collect(tree, by, args);
+	let total: u64 = groups.values().map(|g| g.size).sum();
+
+	// Largest groups first, ties by name so the table is stable
+	let mut groups: Vec<(String, Group)> = groups.into_iter().collect();
+	groups.sort_by(|a, b| b.1.size.cmp(&a.1.size).then_with(|| a.0.cmp(&b.0)));
+
+	let header = [if by == Breakdown::Ext { "Extension" } else { "Category" }, "Files", "Size", "Share", "Largest"];
+	let mut rows: Vec<[String; 5]> = vec![header.map(|h| h.to_string())];
+	for (name, g) in &groups {
+		let share = if total == 0 { 0.0 } else { g.size as f64 * 100.0 / total as f64 };
+		rows.push([name.clone(), g.count.to_string(), fmt_size(g.size, args), format!("{:.1}%", share), g.largest.1.clone()]);
+	}
+
+	let widths: Vec<usize> = (0..4).map(|i| rows.iter().map(|r| r[i].chars().count()).max().unwrap_or(0)).collect();
+	rows.iter().map(|r| {
+		format!("{:<w0$}  {:>w1$}  {:>w2$}  {:>w3$}  {}", r[0], r[1], r[2], r[3], r[4], w0 = widths[0], w1 = widths[1], w2 = widths[2], w3 = widths[3])
+	}).collect()
+}
@@ -0,0 +1,39 @@
+use std::fs;
+use std::path::Path;
+
+
+
+/// Returns the number of bytes actually allocated on disk for `path`.
+///
+/// This is what `FileInfo::size` reports, so compressed and sparse files count
+/// for what they really occupy rather than their logical length.
+#[cfg(windows)]
+pub fn allocated_size(path: &Path, _meta: &fs::Metadata) -> u64 {
+	use winapi::um::fileapi::{GetCompressedFileSizeW, INVALID_FILE_SIZE};
+	use std::os::windows::ffi::OsStrExt;
+
+	// Using metadata doesn't return the right file size in some cases (compressed files), so ask windows directly
+	let mut high: u32 = 0;
+	let pathos = path.as_os_str();
+	let mut pathw: Vec<u16> = Vec::with_capacity(pathos.len() + 1);
+	pathw.extend(pathos.encode_wide());
+	pathw.push(0);
+	let low = unsafe { GetCompressedFileSizeW(pathw.as_ptr(), &mut high) };
+	if low == INVALID_FILE_SIZE { return 0; }
+	u64::from(high) << 32 | u64::from(low)
+}
+
+/// Returns the number of bytes actually allocated on disk for `path`.
+///
+/// `st_blocks` is always counted in 512 byte units regardless of the filesystem block size.
+#[cfg(unix)]
+pub fn allocated_size(_path: &Path, meta: &fs::Metadata) -> u64 {
+	use std::os::unix::fs::MetadataExt;
+	meta.blocks() * 512
+}
+
+/// Fallback for platforms without a notion of allocated size: use the logical length.
+#[cfg(not(any(windows, unix)))]
+pub fn allocated_size(_path: &Path, meta: &fs::Metadata) -> u64 {
+	meta.len()
+}
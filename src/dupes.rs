@@ -0,0 +1,181 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use crate::{Args, FileInfo, fmt_size, fmt_size_summary};
+use crate::tree::{Tree, FileId};
+
+
+
+/*
+	Duplicate detection for --dupes, over the files left in the tree after filtering
+
+	Candidates are grouped by length, then by a hash of their first PARTIAL_LEN bytes and only the files still
+	sharing both are hashed in full, so most files are never read (or only read once, up to PARTIAL_LEN).
+	The hash is 64 bit FNV-1a: not cryptographic, but stable across runs so %hash% can be compared between them.
+	Since it can collide, files sharing a full hash are compared byte for byte before being reported.
+ */
+
+const PARTIAL_LEN: u64 = 64 * 1024;
+
+/// Files with identical contents.
+pub struct DupeSet {
+	pub hash: u64,
+	pub size: u64,					// of each file, as reported by %size%
+	pub files: Vec<String>,			// paths relative to the start directory
+}
+
+impl DupeSet {
+	/// Bytes that would be freed by keeping only one copy.
+	pub fn wasted(&self) -> u64 { self.size * (self.files.len() as u64 - 1) }
+}
+
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+	bytes.iter().fold(hash, |h, b| (h ^ *b as u64).wrapping_mul(0x100000001b3))
+}
+
+// Hashes up to `limit` bytes of the file
+fn hash_file(path: &Path, limit: u64) -> io::Result<u64> {
+	let mut reader = File::open(path)?.take(limit);
+	let mut buf = vec![0u8; 64 * 1024];
+	let mut hash = 0xcbf29ce484222325;
+	loop {
+		let n = reader.read(&mut buf)?;
+		if n == 0 { return Ok(hash); }
+		hash = fnv1a(hash, &buf[..n]);
+	}
+}
+
+// The full content hash of a file, cached on the FileInfo. None if the file can't be read
+fn full_hash(file: &FileInfo) -> Option<u64> {
+	if let Some(h) = file.hash.get() { return Some(h); }
+	let hash = hash_file(&file.path, u64::MAX).ok()?;
+	file.hash.set(Some(hash));
+	Some(hash)
+}
+
+// Whether two files have the same contents, read in step
+fn same_contents(a: &Path, b: &Path) -> io::Result<bool> {
+	let (mut a, mut b) = (File::open(a)?, File::open(b)?);
+	let (mut buf_a, mut buf_b) = (vec![0u8; 64 * 1024], vec![0u8; 64 * 1024]);
+	loop {
+		let n = a.read(&mut buf_a)?;
+		if n == 0 { return Ok(b.read(&mut buf_b)? == 0); }
+		if b.read_exact(&mut buf_b[..n]).is_err() || buf_a[..n] != buf_b[..n] { return Ok(false); }
+	}
+}
+
+// Splits files that share a hash into groups with identical contents (as indexes into `paths`).
+// A file that can't be read again is left out
+fn identical_groups(paths: &[&Path]) -> Vec<Vec<usize>> {
+	let mut groups: Vec<Vec<usize>> = Vec::new();
+	'files: for (i, path) in paths.iter().enumerate() {
+		for group in &mut groups {
+			match same_contents(paths[group[0]], path) {
+				Ok(true) => { group.push(i); continue 'files; }
+				Ok(false) => {}
+				Err(_) => continue 'files,
+			}
+		}
+		if File::open(path).is_ok() { groups.push(vec![i]); }
+	}
+	groups
+}
+
+/// Computes %hash% for every file in the tree.
+pub fn hash_all(tree: &Tree) {
+	for file in &tree.files { full_hash(file); }
+}
+
+/// Finds the sets of files in the tree with identical contents, largest waste first. Empty files are ignored.
+pub fn find_dupes(tree: &Tree, args: &Args) -> Vec<DupeSet> {
+	// BTreeMap keeps the grouping (and so the output) deterministic
+	let mut by_len: BTreeMap<u64, Vec<FileId>> = BTreeMap::new();
+	for (id, file) in tree.files.iter().enumerate() {
+		if file.apparent_size > 0 { by_len.entry(file.apparent_size).or_default().push(id); }
+	}
+
+	let mut sets = Vec::new();
+	for (len, same_len) in by_len {
+		if same_len.len() < 2 { continue; }
+
+		// Files that fit in the partial read are fully hashed by it
+		let mut by_partial: HashMap<u64, Vec<FileId>> = HashMap::new();
+		for id in same_len {
+			let file = &tree.files[id];
+			let partial = if len <= PARTIAL_LEN { full_hash(file) } else { hash_file(&file.path, PARTIAL_LEN).ok() };
+			if let Some(h) = partial { by_partial.entry(h).or_default().push(id); }
+		}
+
+		let mut by_full: HashMap<u64, Vec<FileId>> = HashMap::new();
+		for (_, candidates) in by_partial.into_iter().filter(|(_, c)| c.len() > 1) {
+			for id in candidates {
+				if let Some(h) = full_hash(&tree.files[id]) { by_full.entry(h).or_default().push(id); }
+			}
+		}
+
+		for (hash, same_hash) in by_full.into_iter().filter(|(_, s)| s.len() > 1) {
+			let candidates: Vec<&Path> = same_hash.iter().map(|&id| tree.files[id].path.as_path()).collect();
+			for group in identical_groups(&candidates).into_iter().filter(|g| g.len() > 1) {
+				let same: Vec<FileId> = group.iter().map(|&i| same_hash[i]).collect();
+				let mut paths: Vec<String> = same.iter().map(|&id| {
+				let f = &tree.files[id];
+					f.path.strip_prefix(&tree.root().path).unwrap_or(&f.path).to_string_lossy().to_string()
+				}).collect();
+				paths.sort();
+				sets.push(DupeSet { hash, size: tree.files[same[0]].size(args.apparent_size), files: paths });
+			}
+		}
+	}
+
+	sets.sort_by(|a, b| b.wasted().cmp(&a.wasted()).then_with(|| a.files.cmp(&b.files)));
+	sets
+}
+
+/// The `dupes` summary line.
+pub fn dupes_summary(sets: &[DupeSet], args: &Args) -> String {
+	let files: usize = sets.iter().map(|s| s.files.len()).sum();
+	let wasted: u64 = sets.iter().map(|s| s.wasted()).sum();
+	format!("Duplicates: {} sets, {} files, {} wasted", sets.len(), files, fmt_size_summary(wasted, args))
+}
+
+/// The --dupes report printed after the tree.
+pub fn dupes_lines(sets: &[DupeSet], args: &Args) -> Vec<String> {
+	let mut lines = vec![dupes_summary(sets, args)];
+	for set in sets {
+		lines.push(String::new());
+		lines.push(format!("{} x {} ({} wasted) {:016x}", set.files.len(), fmt_size(set.size, args), fmt_size(set.wasted(), args), set.hash));
+		lines.extend(set.files.iter().map(|f| format!("  {}", f)));
+	}
+	lines
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::fs;
+
+	#[test]
+	fn files_sharing_a_hash_are_compared_byte_for_byte() {
+		let dir = std::env::temp_dir().join(format!("treee-dupes-{}", std::process::id()));
+		fs::create_dir_all(&dir).unwrap();
+		let write = |name: &str, contents: &[u8]| { let p = dir.join(name); fs::write(&p, contents).unwrap(); p };
+		let big: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+		let mut big_changed = big.clone();
+		big_changed[150_000] ^= 1;
+
+		let a = write("a", b"same");
+		let b = write("b", b"diff");
+		let c = write("c", b"same");
+		let d = write("d", b"same but longer");
+		let e = write("e", &big);
+		let f = write("f", &big_changed);
+		let g = write("g", &big);
+		let missing = dir.join("missing");
+
+		let groups = identical_groups(&[&a, &b, &missing, &c, &d, &e, &f, &g]);
+		assert_eq!(groups, vec![vec![0, 3], vec![1], vec![4], vec![5, 7], vec![6]]);
+		fs::remove_dir_all(&dir).unwrap();
+	}
+}
@@ -0,0 +1,382 @@
+use std::fs;
+use std::path::Path;
+use anyhow::Result;
+use regex::{Regex, RegexBuilder};
+
+use crate::{MetaSearch, MetaOperator, MetaPart, META_FIELDS, matches_metadata, regex_error};
+
+
+
+/*
+	Boolean filter expressions for --where
+
+	expr    := and ( (OR | "||") and )*
+	and     := not ( (AND | "&&") not )*
+	not     := (NOT | "!") not | primary
+	primary := "(" expr ")" | term
+	term    := field op value
+
+	fields: name, path, ext, type (file|dir) and the -m metadata fields (size, modified, created, accessed, readonly)
+	ops:    ~ (regex), ^~ (case insensitive regex), = or is, ^= (case insensitive equality), != or "is not", >, >=, <, <=
+	values: a bare word (ends at whitespace or an unbalanced ')') or a '...' / "..." quoted string
+
+	e.g. (ext is rs OR ext is toml) AND size > 10000 AND NOT name ~ test
+ */
+
+#[derive(Debug)]
+enum Expr {
+	And(Box<Expr>, Box<Expr>),
+	Or(Box<Expr>, Box<Expr>),
+	Not(Box<Expr>),
+	Term(usize),	// index into FilterExpr::terms
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+	Regex,
+	RegexNoCase,
+	Equals,
+	EqualsNoCase,
+	NotEquals,
+	Greater,
+	GreaterEq,
+	Less,
+	LessEq,
+}
+
+#[derive(Debug)]
+enum Term {
+	Name(Op, String, Option<Regex>),
+	Path(Op, String, Option<Regex>),
+	Ext(Op, String, Option<Regex>),
+	IsDir(bool, bool),					// (wanted is_dir, negated)
+	Meta(MetaSearch),
+}
+
+/// A parsed --where expression.
+#[derive(Debug)]
+pub struct FilterExpr {
+	expr: Expr,
+	terms: Vec<Term>,
+}
+
+const TEXT_FIELDS: &[&str] = &["name", "path", "ext"];
+
+// Longest operators first so "^~" isn't read as "^" and ">=" isn't read as ">"
+const OPS: &[(&str, Op)] = &[
+	("^~", Op::RegexNoCase),
+	("^=", Op::EqualsNoCase),
+	("!=", Op::NotEquals),
+	(">=", Op::GreaterEq),
+	("<=", Op::LessEq),
+	("~", Op::Regex),
+	("=", Op::Equals),
+	(">", Op::Greater),
+	("<", Op::Less),
+];
+
+struct Parser<'a> {
+	src: &'a str,
+	pos: usize,
+	terms: Vec<Term>,
+}
+
+impl<'a> Parser<'a> {
+	fn error(&self, pos: usize, msg: &str) -> anyhow::Error {
+		let column = self.src[..pos].chars().count();
+		anyhow::anyhow!("Invalid --where expression: {} at column {}\n  {}\n  {}^", msg, column + 1, self.src, " ".repeat(column))
+	}
+
+	fn rest(&self) -> &'a str { &self.src[self.pos..] }
+
+	fn skip_ws(&mut self) {
+		let trimmed = self.rest().trim_start();
+		self.pos = self.src.len() - trimmed.len();
+	}
+
+	// Consumes `s` if it comes next
+	fn eat(&mut self, s: &str) -> bool {
+		self.skip_ws();
+		if self.rest().starts_with(s) { self.pos += s.len(); return true; }
+		false
+	}
+
+	// Consumes a case insensitive keyword if it comes next as a whole word
+	fn eat_keyword(&mut self, kw: &str) -> bool {
+		self.skip_ws();
+		let rest = self.rest();
+		if rest.get(..kw.len()).is_some_and(|p| p.eq_ignore_ascii_case(kw)) {
+			let next = rest[kw.len()..].chars().next();
+			if next.is_none_or(|c| c.is_whitespace() || c == '(' || c == ')') {
+				self.pos += kw.len();
+				return true;
+			}
+		}
+		false
+	}
+
+	fn parse_or(&mut self) -> Result<Expr> {
+		let mut lhs = self.parse_and()?;
+		while self.eat_keyword("or") || self.eat("||") {
+			let rhs = self.parse_and()?;
+			lhs = Expr::Or(Box::new(lhs), Box::new(rhs));
+		}
+		Ok(lhs)
+	}
+
+	fn parse_and(&mut self) -> Result<Expr> {
+		let mut lhs = self.parse_not()?;
+		while self.eat_keyword("and") || self.eat("&&") {
+			let rhs = self.parse_not()?;
+			lhs = Expr::And(Box::new(lhs), Box::new(rhs));
+		}
+		Ok(lhs)
+	}
+
+	fn parse_not(&mut self) -> Result<Expr> {
+		self.skip_ws();
+		if self.eat_keyword("not") || (self.rest().starts_with('!') && !self.rest().starts_with("!=") && self.eat("!")) {
+			return Ok(Expr::Not(Box::new(self.parse_not()?)));
+		}
+		self.parse_primary()
+	}
+
+	fn parse_primary(&mut self) -> Result<Expr> {
+		if self.eat("(") {
+			let expr = self.parse_or()?;
+			if !self.eat(")") { return Err(self.error(self.pos, "expected ')'")); }
+			return Ok(expr);
+		}
+		self.parse_term()
+	}
+
+	fn parse_term(&mut self) -> Result<Expr> {
+		self.skip_ws();
+		let start = self.pos;
+		let field_len = self.rest().find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(self.rest().len());
+		if field_len == 0 {
+			return Err(self.error(start, if self.rest().is_empty() { "expected a term" } else { "expected a field name" }));
+		}
+		let field = self.rest()[..field_len].to_lowercase();
+		self.pos += field_len;
+
+		if field != "type" && !TEXT_FIELDS.contains(&field.as_str()) && !META_FIELDS.contains(&field.as_str()) {
+			return Err(self.error(start, &format!("unknown field '{}'", field)));
+		}
+
+		// operator
+		let op_pos = { self.skip_ws(); self.pos };
+		let op = if self.eat_keyword("is") {
+			if self.eat_keyword("not") { Op::NotEquals } else { Op::Equals }
+		} else {
+			match OPS.iter().find(|(s, _)| self.rest().starts_with(s)) {
+				Some((s, op)) => { self.pos += s.len(); *op }
+				None => return Err(self.error(op_pos, "expected an operator (~, ^~, =, is, ^=, !=, >, >=, <, <=)")),
+			}
+		};
+
+		let value_pos = { self.skip_ws(); self.pos };
+		let value = self.parse_value()?;
+
+		let term = match field.as_str() {
+			"type" => {
+				let is_dir = match value.to_lowercase().as_str() {
+					"dir" | "directory" | "d" => true,
+					"file" | "f" => false,
+					_ => return Err(self.error(value_pos, "type must be 'file' or 'dir'")),
+				};
+				match op {
+					Op::Equals | Op::EqualsNoCase => Term::IsDir(is_dir, false),
+					Op::NotEquals => Term::IsDir(is_dir, true),
+					_ => return Err(self.error(op_pos, "type only supports =, is and !=")),
+				}
+			}
+			"name" | "path" | "ext" => {
+				let regex = match op {
+					Op::Regex | Op::RegexNoCase => Some(RegexBuilder::new(&value).case_insensitive(op == Op::RegexNoCase).build()
+						.map_err(|e| self.error(value_pos, &format!("invalid regex ({})", regex_error(&e))))?),
+					Op::Equals | Op::EqualsNoCase | Op::NotEquals => None,
+					_ => return Err(self.error(op_pos, &format!("{} can't be compared with <, >, <= or >=", field))),
+				};
+				match field.as_str() {
+					"name" => Term::Name(op, value, regex),
+					"path" => Term::Path(op, value, regex),
+					_ => Term::Ext(op, value.trim_start_matches('.').to_string(), regex),
+				}
+			}
+			_ => {
+				let operator = match op {
+					Op::Regex | Op::RegexNoCase => MetaOperator::Regex,
+					Op::Equals | Op::EqualsNoCase => MetaOperator::Equals,
+					Op::NotEquals => MetaOperator::NotEquals,
+					Op::Greater => MetaOperator::GreaterThan,
+					Op::GreaterEq => MetaOperator::GreaterEq,
+					Op::Less => MetaOperator::LessThan,
+					Op::LessEq => MetaOperator::LessEq,
+				};
+				let case_insensitive = op == Op::RegexNoCase || op == Op::EqualsNoCase;
+				Term::Meta(MetaSearch::new(&field, operator, case_insensitive, &value).map_err(|(part, e)| match part {
+					MetaPart::Field => self.error(start, &e),
+					MetaPart::Operator => self.error(op_pos, &e),
+					MetaPart::Value => self.error(value_pos, &e),
+				})?)
+			}
+		};
+
+		self.terms.push(term);
+		Ok(Expr::Term(self.terms.len() - 1))
+	}
+
+	fn parse_value(&mut self) -> Result<String> {
+		let start = self.pos;
+		let rest = self.rest();
+		let quote = rest.chars().next().filter(|&c| c == '\'' || c == '"');
+
+		if let Some(q) = quote {
+			// Quoted: '...' is taken verbatim, "..." allows \" to embed a quote
+			let mut value = String::new();
+			let mut chars = rest.char_indices().skip(1);
+			while let Some((i, c)) = chars.next() {
+				if c == q { self.pos += i + 1; return Ok(value); }
+				if c == '\\' && q == '"' && rest[i + 1..].starts_with('"') { chars.next(); value.push('"'); continue; }
+				value.push(c);
+			}
+			return Err(self.error(start, "unterminated string"));
+		}
+
+		// Bare: up to whitespace or a ')' that closes a group opened outside of the value
+		let mut depth = 0;
+		let mut end = rest.len();
+		for (i, c) in rest.char_indices() {
+			if c.is_whitespace() { end = i; break; }
+			if c == '(' { depth += 1; }
+			if c == ')' { if depth == 0 { end = i; break; } depth -= 1; }
+		}
+		if end == 0 { return Err(self.error(start, "expected a value")); }
+		self.pos += end;
+		Ok(rest[..end].to_string())
+	}
+}
+
+fn matches_text(text: &str, op: Op, value: &str, regex: &Option<Regex>) -> bool {
+	match op {
+		Op::Regex | Op::RegexNoCase => regex.as_ref().is_some_and(|r| r.is_match(text)),
+		Op::Equals => text == value,
+		Op::EqualsNoCase => text.to_lowercase() == value.to_lowercase(),
+		Op::NotEquals => text != value,
+		_ => false,
+	}
+}
+
+impl FilterExpr {
+	/// Parses a --where expression. Errors point at the offending column.
+	pub fn parse(src: &str) -> Result<FilterExpr> {
+		let mut parser = Parser { src, pos: 0, terms: Vec::new() };
+		let expr = parser.parse_or()?;
+		parser.skip_ws();
+		if parser.pos < src.len() {
+			return Err(parser.error(parser.pos, "expected AND, OR or the end of the expression"));
+		}
+		Ok(FilterExpr { expr, terms: parser.terms })
+	}
+
+	/// Evaluates the expression for an entry, returning the result and the result of each term in the order they were written.
+	pub fn eval(&self, name: &str, path: &Path, is_dir: bool, metadata: Option<&fs::Metadata>) -> (bool, Vec<bool>) {
+		let terms: Vec<bool> = self.terms.iter().map(|t| match t {
+			Term::Name(op, value, regex) => matches_text(name, *op, value, regex),
+			Term::Path(op, value, regex) => matches_text(&path.to_string_lossy(), *op, value, regex),
+			Term::Ext(op, value, regex) => {
+				let ext = path.extension().map(|e| e.to_string_lossy().to_string()).unwrap_or_default();
+				matches_text(&ext, *op, value, regex)
+			}
+			Term::IsDir(want, negated) => (is_dir == *want) != *negated,
+			Term::Meta(search) => metadata.is_some_and(|m| matches_metadata(m, search)),
+		}).collect();
+
+		(eval_expr(&self.expr, &terms), terms)
+	}
+}
+
+fn eval_expr(expr: &Expr, terms: &[bool]) -> bool {
+	match expr {
+		Expr::And(a, b) => eval_expr(a, terms) && eval_expr(b, terms),
+		Expr::Or(a, b) => eval_expr(a, terms) || eval_expr(b, terms),
+		Expr::Not(a) => !eval_expr(a, terms),
+		Expr::Term(i) => terms[*i],
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn shape(src: &str) -> String {
+		format!("{:?}", FilterExpr::parse(src).unwrap().expr)
+	}
+
+	fn error(src: &str) -> String {
+		FilterExpr::parse(src).unwrap_err().to_string()
+	}
+
+	fn eval_name(src: &str, name: &str) -> bool {
+		FilterExpr::parse(src).unwrap().eval(name, Path::new(name), false, None).0
+	}
+
+	#[test]
+	fn and_binds_tighter_than_or() {
+		assert_eq!(shape("name = a OR name = b AND name = c"), "Or(Term(0), And(Term(1), Term(2)))");
+		assert_eq!(shape("name = a && name = b || name = c"), "Or(And(Term(0), Term(1)), Term(2))");
+		assert_eq!(shape("name = a or name = b or name = c"), "Or(Or(Term(0), Term(1)), Term(2))");
+	}
+
+	#[test]
+	fn parentheses_group() {
+		assert_eq!(shape("(name = a OR name = b) AND name = c"), "And(Or(Term(0), Term(1)), Term(2))");
+		assert_eq!(shape("((name = a))"), "Term(0)");
+		assert!(!eval_name("(name = a OR name = b) AND name = c", "a"));
+		assert!(eval_name("name = a OR (name = b AND name = c)", "a"));
+		// A ')' inside a value only ends it when it closes a group opened outside
+		assert!(eval_name("(name ~ ^f(o)o$)", "foo"));
+	}
+
+	#[test]
+	fn not_binds_tightest() {
+		assert_eq!(shape("NOT name = a AND name = b"), "And(Not(Term(0)), Term(1))");
+		assert_eq!(shape("!!name = a"), "Not(Not(Term(0)))");
+		assert_eq!(shape("not (name = a or name = b)"), "Not(Or(Term(0), Term(1)))");
+		// != is an operator, not a negation
+		assert_eq!(shape("name != a"), "Term(0)");
+		assert!(eval_name("NOT name ~ test", "main.rs"));
+		assert!(!eval_name("NOT name ~ test", "test.rs"));
+		assert!(eval_name("name is not a", "b"));
+	}
+
+	#[test]
+	fn keywords_need_word_boundaries() {
+		// "order" isn't OR followed by "der"
+		assert!(eval_name("name = order", "order"));
+		assert!(eval_name("name = notes", "notes"));
+	}
+
+	#[test]
+	fn non_ascii_text_after_a_term() {
+		assert!(eval_name("name = é", "é"));
+		assert!(error("name = a é€").contains("expected AND, OR or the end of the expression at column 10"));
+		assert!(error("ééé").contains("expected a field name at column 1"));
+		assert!(error("name = a AND €").contains("expected a field name at column 14"));
+	}
+
+	#[test]
+	fn errors_point_at_the_column() {
+		assert!(error("name = a AND").contains("expected a term at column 13"));
+		assert!(error("(name = a").contains("expected ')' at column 10"));
+		assert!(error("colour = red").contains("unknown field 'colour' at column 1"));
+		assert!(error("name  a").contains("expected an operator (~, ^~, =, is, ^=, !=, >, >=, <, <=) at column 7"));
+		assert!(error("name = a b").contains("expected AND, OR or the end of the expression at column 10"));
+		assert!(error("name ~ 'a").contains("unterminated string at column 8"));
+		assert!(error("type = link").contains("type must be 'file' or 'dir' at column 8"));
+		assert!(error("size > lots").contains("at column 8"));
+		// Columns count characters, not bytes
+		assert!(error("name = é AND").contains("at column 13"));
+	}
+}
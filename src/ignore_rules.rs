@@ -0,0 +1,194 @@
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+
+
+
+/// The ignore files in effect for one directory, linked to those of its parent directory.
+///
+/// Precedence follows ripgrep: `.ignore` files (deepest first), then `.gitignore` files (deepest first),
+/// then `.git/info/exclude` and finally git's `core.excludesFile`. The first rule that matches wins, so a
+/// deeper `!pattern` can re-include what a higher file ignored. Also like ripgrep, the git rules only apply inside
+/// a repository, and `.git` directories are always ignored.
+#[derive(Debug)]
+pub struct IgnoreRules {
+	dir: PathBuf,					// absolute path of the directory
+	ignore: Option<Gitignore>,		// .ignore
+	gitignore: Option<Gitignore>,	// .gitignore
+	exclude: Option<Gitignore>,		// .git/info/exclude, only for a repository root
+	global: Rc<Gitignore>,			// core.excludesFile, shared by the whole walk
+	in_repo: bool,					// this directory or an ancestor has a .git, so the git rules apply
+	parent: Option<Rc<IgnoreRules>>,
+}
+
+// Loads a single ignore file, unreadable files give no rules
+fn load(root: &Path, file: &Path) -> Option<Gitignore> {
+	let mut builder = GitignoreBuilder::new(root);
+	builder.add(file);
+	builder.build().ok().filter(|g| !g.is_empty())
+}
+
+fn check(gitignore: &Option<Gitignore>, path: &Path, is_dir: bool) -> Match<()> {
+	match gitignore {
+		Some(g) => g.matched(path, is_dir).map(|_| ()),
+		None => Match::None,
+	}
+}
+
+impl IgnoreRules {
+	/// Creates the rules for the directory the walk starts in.
+	///
+	/// When `path` is inside a git repository the ignore files of every directory from the repository root
+	/// down to `path` are loaded, so starting the walk in a subdirectory gives the same result as walking the whole repo.
+	pub fn root(path: &Path) -> Rc<IgnoreRules> {
+		let path = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+
+		let repo_root = path.ancestors().find(|p| p.join(".git").exists()).map(|p| p.to_path_buf());
+		let (global, _) = GitignoreBuilder::new(repo_root.as_deref().unwrap_or(&path)).build_global();
+		let global = Rc::new(global);
+
+		// Directories above the start path are only consulted inside a repository
+		let mut dirs: Vec<&Path> = match &repo_root {
+			Some(repo_root) => path.ancestors().take_while(|p| p.starts_with(repo_root)).collect(),
+			None => vec![&path],
+		};
+		dirs.reverse();
+
+		let mut rules: Option<Rc<IgnoreRules>> = None;
+		for dir in dirs {
+			rules = Some(Rc::new(IgnoreRules::load_dir(dir, |name| dir.join(name).exists(), global.clone(), rules)));
+		}
+		rules.unwrap()
+	}
+
+	/// Creates the rules for the subdirectory `name` of this directory, given the names listed in it.
+	///
+	/// Only the ignore files among `names` are opened, so walking a directory without any costs nothing more than its listing.
+	pub fn child(self: &Rc<Self>, name: &str, names: &[&str]) -> Rc<IgnoreRules> {
+		Rc::new(IgnoreRules::load_dir(&self.dir.join(name), |n| names.contains(&n), self.global.clone(), Some(self.clone())))
+	}
+
+	// `has` tells whether the directory contains an entry
+	fn load_dir(dir: &Path, has: impl Fn(&str) -> bool, global: Rc<Gitignore>, parent: Option<Rc<IgnoreRules>>) -> IgnoreRules {
+		let is_repo_root = has(".git");
+		let in_repo = parent.as_ref().is_some_and(|p| p.in_repo) || is_repo_root;
+		IgnoreRules {
+			dir: dir.to_path_buf(),
+			ignore: if has(".ignore") { load(dir, &dir.join(".ignore")) } else { None },
+			gitignore: if in_repo && has(".gitignore") { load(dir, &dir.join(".gitignore")) } else { None },
+			exclude: if is_repo_root { load(dir, &dir.join(".git").join("info").join("exclude")) } else { None },
+			global,
+			in_repo,
+			parent,
+		}
+	}
+
+	/// Returns true if the entry `name` of this directory is ignored.
+	pub fn is_ignored(&self, name: &str, is_dir: bool) -> bool {
+		if is_dir && name == ".git" { return true; }
+		let path = self.dir.join(name);
+
+		let mut levels: Vec<&IgnoreRules> = vec![self];
+		while let Some(p) = &levels[levels.len() - 1].parent { levels.push(p); }
+
+		let checks = levels.iter().map(|l| check(&l.ignore, &path, is_dir))
+			.chain(levels.iter().map(|l| check(&l.gitignore, &path, is_dir)))
+			.chain(levels.iter().map(|l| check(&l.exclude, &path, is_dir)));
+		for m in checks {
+			match m {
+				Match::Ignore(_) => return true,
+				Match::Whitelist(_) => return false,
+				Match::None => {}
+			}
+		}
+
+		self.in_repo && self.global.matched(&path, is_dir).is_ignore()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::fs;
+
+	// A fresh directory under the system temp dir, removed when dropped
+	struct TempDir(PathBuf);
+
+	impl TempDir {
+		fn new(name: &str) -> TempDir {
+			let dir = std::env::temp_dir().join(format!("treee-ignore-{}-{}", name, std::process::id()));
+			let _ = fs::remove_dir_all(&dir);
+			fs::create_dir_all(&dir).unwrap();
+			TempDir(dir)
+		}
+
+		fn write(&self, file: &str, contents: &str) {
+			let path = self.0.join(file);
+			fs::create_dir_all(path.parent().unwrap()).unwrap();
+			fs::write(path, contents).unwrap();
+		}
+	}
+
+	impl Drop for TempDir {
+		fn drop(&mut self) { let _ = fs::remove_dir_all(&self.0); }
+	}
+
+	#[test]
+	fn gitignore_needs_a_repository() {
+		let tmp = TempDir::new("norepo");
+		tmp.write(".gitignore", "*.log\n");
+		tmp.write(".ignore", "*.tmp\n");
+		let rules = IgnoreRules::root(&tmp.0);
+		assert!(!rules.is_ignored("build.log", false));
+		assert!(rules.is_ignored("scratch.tmp", false));
+	}
+
+	#[test]
+	fn gitignore_applies_inside_a_repository() {
+		let tmp = TempDir::new("repo");
+		fs::create_dir(tmp.0.join(".git")).unwrap();
+		tmp.write(".gitignore", "*.log\n");
+		tmp.write(".git/info/exclude", "secret\n");
+		tmp.write("sub/.gitignore", "!keep.log\n");
+		let rules = IgnoreRules::root(&tmp.0);
+		assert!(rules.is_ignored("build.log", false));
+		assert!(rules.is_ignored("secret", false));
+		let sub = rules.child("sub", &[".gitignore"]);
+		assert!(sub.is_ignored("other.log", false));
+		assert!(!sub.is_ignored("keep.log", false));
+
+		// Starting below the repository root still finds it
+		let rules = IgnoreRules::root(&tmp.0.join("sub"));
+		assert!(rules.is_ignored("other.log", false));
+	}
+
+	#[test]
+	fn nested_repository_starts_applying_git_rules() {
+		let tmp = TempDir::new("nested");
+		tmp.write("project/.gitignore", "target/\n");
+		fs::create_dir(tmp.0.join("project/.git")).unwrap();
+		tmp.write("other/.gitignore", "target/\n");
+		let rules = IgnoreRules::root(&tmp.0);
+		assert!(rules.child("project", &[".git", ".gitignore"]).is_ignored("target", true));
+		assert!(!rules.child("other", &[".gitignore"]).is_ignored("target", true));
+	}
+
+	#[test]
+	fn child_only_loads_listed_files() {
+		let tmp = TempDir::new("listed");
+		tmp.write("sub/.ignore", "*.tmp\n");
+		let rules = IgnoreRules::root(&tmp.0);
+		assert!(rules.child("sub", &[".ignore"]).is_ignored("scratch.tmp", false));
+		assert!(!rules.child("sub", &["scratch.tmp"]).is_ignored("scratch.tmp", false));
+	}
+
+	#[test]
+	fn git_dir_is_always_ignored() {
+		let tmp = TempDir::new("gitdir");
+		let rules = IgnoreRules::root(&tmp.0);
+		assert!(rules.is_ignored(".git", true));
+		assert!(!rules.is_ignored(".git", false));
+		assert!(!rules.is_ignored(".github", true));
+	}
+}
@@ -1,17 +1,27 @@
 #![allow(unused)]
 #![debugger_visualizer(natvis_file = "treee.natvis")]
 //#![debugger_visualizer(natvis_file = "../intrinsic.natvis")]
-mod parent_ref;
+mod disk_size;
+mod ignore_rules;
+mod filter_expr;
+mod units;
+mod time_spec;
+mod output;
+mod sort;
+mod top;
+mod breakdown;
+mod dupes;
+mod walker;
+mod tree;
+mod stream;
 
 #[macro_use]
 mod extend;
 
-use parent_ref::ParentRef;
 
 
 /*
 	TODO: Currently -p <str> doesn't differentiate between dirs and files. this can make it hard to get appropriate strings. So we should somehow specify a way to do dir and/or file string
-	Add some way to do an anti-match which will prevent such matches
 	TODO: There seems to be a bug when specifying only -d, we stil get files
  */
 
@@ -20,14 +30,25 @@ use clap::Parser;
 use colored::*;
 use std::path::{Path, PathBuf};
 use std::fs;
-use regex::Regex;
+use std::io;
+use regex::{Regex, RegexBuilder};
 use std::rc::{Rc};
-use std::cell::RefCell;
+use std::cell::Cell;
+use std::ops::Range;
 use std::time::SystemTime;
 use chrono::offset::Utc; use chrono::DateTime;use chrono::SecondsFormat;
 
-use winapi::um::fileapi::{GetCompressedFileSizeW, INVALID_FILE_SIZE};
-use std::os::windows::ffi::OsStrExt;
+use ignore_rules::IgnoreRules;
+use filter_expr::FilterExpr;
+use units::{SizeUnits, format_size, parse_size};
+use sort::{SortKey, compare_names};
+use top::{TopKind, top_lines};
+use breakdown::{Breakdown, breakdown_lines};
+use dupes::{DupeSet, find_dupes, hash_all, dupes_lines, dupes_summary};
+use walker::{ListedEntry, Walker};
+use output::{FileMatcher, DirMatcher};
+use tree::{Tree, DirId, FileId, ROOT};
+use time_spec::{TimeError, TimeSpec, parse_time_spec, parse_time_range, time_less, time_greater, time_equals};
 
 
 // https://doc.rust-lang.org/std/fs/struct.Metadata.html
@@ -107,6 +128,16 @@ const FILE_COLORS: &[fn(&str) -> ColoredString] = &[
     |s| s.truecolor(210, 105, 30),     // Chocolate
 ];
 
+// CSS versions of DIR_COLORS and FILE_COLORS for the html output, keep them in the same order
+const DIR_CSS_COLORS: &[&str] = &["#3b78ff", "#16c60c", "#13a10e", "#61d6d6", "#3a96dd", "rgb(70,130,180)", "rgb(46,139,87)"];
+const FILE_CSS_COLORS: &[&str] = &["#c19c00", "#881798", "rgb(255,165,0)", "rgb(255,105,180)", "rgb(128,0,128)", "rgb(0,128,128)", "rgb(139,69,19)", "rgb(210,105,30)"];
+
+// The palette index for an entry's match vector, None when nothing matched
+fn color_index(match_details: &[bool], is_directory: bool) -> Option<usize> {
+	let len = if is_directory { DIR_COLORS.len() } else { FILE_COLORS.len() };
+	match_details.iter().position(|&m| m).map(|i| i % len)
+}
+
 // Alternative: Combine all matching colors (for multiple matches)
 fn get_combined_color(
     name: &str,
@@ -115,22 +146,53 @@ fn get_combined_color(
 ) -> ColoredString {
     let color_palettes = if is_directory { DIR_COLORS } else { FILE_COLORS };
 
-    // Find all matching indices
-    let matching_indices: Vec<usize> = match_details
-        .iter()
-        .enumerate()
-        .filter_map(|(i, &m)| if m { Some(i) } else { None })
-        .collect();
+    // For multiple matches, use the first one (or you could blend colors, but that's complex in terminal)
+    match color_index(match_details, is_directory) {
+		Some(color_idx) => (color_palettes[color_idx])(name),
+		None => if is_directory { name.bright_blue() } else { name.red() },
+	}
+}
 
-    if matching_indices.is_empty() {
-        return if is_directory { name.bright_blue() } else { name.red() };
-    }
+// Ignored entries shown with --show-ignored are dimmed
+fn dim_ignored(name: ColoredString, ignored: bool) -> ColoredString {
+	if ignored { name.dimmed() } else { name }
+}
 
-    // For multiple matches, use the first one (or you could blend colors, but that's complex in terminal)
-    let color_idx = matching_indices[0] % color_palettes.len();
-    (color_palettes[color_idx])(name)
+// What's printed after the name instead of the format info: why the entry couldn't be read, or the info dimmed
+fn info_or_error(info: String, error: &Option<String>) -> ColoredString {
+	match error {
+		Some(e) => format!("[{}]", e).red(),
+		None => info.dimmed(),
+	}
 }
 
+// The short reason shown for an entry that couldn't be read
+fn error_message(e: &io::Error) -> String {
+	match e.kind() {
+		io::ErrorKind::PermissionDenied => "permission denied".to_string(),
+		io::ErrorKind::NotFound => "vanished".to_string(),
+		_ => e.to_string(),
+	}
+}
+
+// A listed entry's metadata, or the reason it couldn't be stat'ed
+fn split_metadata(metadata: io::Result<fs::Metadata>) -> (Option<fs::Metadata>, Option<String>) {
+	match metadata {
+		Ok(m) => (Some(m), None),
+		Err(e) => (None, Some(error_message(&e))),
+	}
+}
+
+// The summary line counting the entries that couldn't be read, also the --strict error
+fn unreadable_summary(errors: u64) -> String {
+	format!("{} {} could not be read", errors, if errors == 1 { "entry" } else { "entries" })
+}
+
+// --strict fails the run, after the output, when anything couldn't be read
+fn check_strict(root: &DirInfo, args: &Args) -> Result<()> {
+	if args.strict && root.total_errors > 0 { anyhow::bail!(unreadable_summary(root.total_errors)); }
+	Ok(())
+}
 
 
 
@@ -139,98 +201,214 @@ struct MetaSearch {
     field: String,
     pattern: String,
     operator: MetaOperator,
+	case_insensitive: bool,	// the ^ operators, only affects ~ and text comparisons
+	value: MetaValue,		// pattern parsed for the field
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 enum MetaOperator {
     Equals,
+	NotEquals,
     GreaterThan,
+	GreaterEq,
     LessThan,
-    Contains,
+	LessEq,
     Regex,
 }
 
-// Parse metadata search strings
-fn parse_meta_search(search: &str) -> Result<MetaSearch, String> {
-    // Format: "field:operator:pattern" or "field:pattern" (default to contains)
+// The typed value a MetaSearch compares against
+#[derive(Debug)]
+enum MetaValue {
+	Size(u64),
+	Time(TimeSpec),
+	TimeRange(Option<SystemTime>, Option<SystemTime>),
+	Bool(bool),
+	Regex(Regex),
+}
+
+// Which part of a metadata search an error is about
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MetaPart {
+	Field,
+	Operator,
+	Value,
+}
+
+const META_FIELDS: &[&str] = &["size", "modified", "created", "accessed", "readonly"];
+
+impl MetaSearch {
+	/// Builds a typed search, failing for unknown fields, operators the field can't use or values that don't parse.
+	fn new(field: &str, operator: MetaOperator, case_insensitive: bool, pattern: &str) -> Result<MetaSearch, (MetaPart, String)> {
+		if !META_FIELDS.contains(&field) {
+			return Err((MetaPart::Field, format!("unknown field '{}' (expected one of {})", field, META_FIELDS.join(", "))));
+		}
+
+		let value = if operator == MetaOperator::Regex {
+			MetaValue::Regex(regex::RegexBuilder::new(pattern).case_insensitive(case_insensitive).build().map_err(|e| (MetaPart::Value, format!("invalid regex ({})", regex_error(&e))))?)
+		} else {
+			match field {
+				"size" => MetaValue::Size(parse_size(pattern).ok_or((MetaPart::Value, format!("invalid size '{}' (e.g. 1024, 10K, 1.5MiB, 2G)", pattern)))?),
+				"readonly" => {
+					if operator != MetaOperator::Equals && operator != MetaOperator::NotEquals { return Err((MetaPart::Operator, "readonly only supports =, != and ~".to_string())); }
+					MetaValue::Bool(match pattern.to_lowercase().as_str() {
+						"true" | "yes" | "1" => true,
+						"false" | "no" | "0" => false,
+						_ => return Err((MetaPart::Value, format!("invalid readonly value '{}' (expected true or false)", pattern))),
+					})
+				}
+				_ => {
+					let now = SystemTime::now();
+					let time_error = |e| (MetaPart::Value, match e {
+						TimeError::OutOfRange => format!("duration out of range '{}'", pattern),
+						TimeError::Invalid => format!("invalid time '{}' (e.g. 7d, 3h, 2025-01-31, 2025-01..2025-03)", pattern),
+					});
+					if let Some((from, to)) = parse_time_range(pattern, now).map_err(time_error)? {
+						if operator != MetaOperator::Equals && operator != MetaOperator::NotEquals { return Err((MetaPart::Operator, "time ranges only support = and !=".to_string())); }
+						MetaValue::TimeRange(from, to)
+					} else {
+						MetaValue::Time(parse_time_spec(pattern, now).map_err(time_error)?)
+					}
+				}
+			}
+		};
+
+		Ok(MetaSearch { field: field.to_string(), pattern: pattern.to_string(), operator, case_insensitive, value })
+	}
+}
+
+// Parse metadata search strings. Errors carry the byte range of the offending part
+fn parse_meta_search(search: &str) -> Result<MetaSearch, (std::ops::Range<usize>, String)> {
+    // Format: "field:operator:pattern" or "field:pattern" (a regex, or a time range such as 2025-01..2025-03)
     let parts: Vec<&str> = search.splitn(3, ':').collect();
 
     match parts.len() {
-        2 => Ok(MetaSearch {
-            field: parts[0].to_string(),
-            operator: MetaOperator::Contains, // default
-            pattern: parts[1].to_string(),
-        }),
+        2 => {
+			let time_range = ["modified", "created", "accessed"].contains(&parts[0]) && matches!(parse_time_range(parts[1], SystemTime::now()), Ok(Some(_)));
+			let span = |part| if part == MetaPart::Field { 0..parts[0].len() } else { parts[0].len()+1..search.len() };
+			MetaSearch::new(parts[0], if time_range { MetaOperator::Equals } else { MetaOperator::Regex }, false, parts[1]).map_err(|(part, e)| (span(part), e))
+		}
         3 => {
-            let operator = match parts[1] {
-                ">" => MetaOperator::GreaterThan,
-                "<" => MetaOperator::LessThan,
-                "=" => MetaOperator::Equals,
-                "~" => MetaOperator::Contains,
-                "^>" => MetaOperator::GreaterThan,
-                "^<" => MetaOperator::LessThan,
-                "^=" => MetaOperator::Equals,
-                "^~" => MetaOperator::Contains,
-                _ => MetaOperator::Regex,
+			// The ^ prefixed operators are the case insensitive versions
+			let (case_insensitive, op) = match parts[1].strip_prefix('^') { Some(op) => (true, op), None => (false, parts[1]) };
+            let operator = match op {
+                ">" => Some(MetaOperator::GreaterThan),
+                ">=" => Some(MetaOperator::GreaterEq),
+                "<" => Some(MetaOperator::LessThan),
+                "<=" => Some(MetaOperator::LessEq),
+                "=" => Some(MetaOperator::Equals),
+                "!=" => Some(MetaOperator::NotEquals),
+                "~" => Some(MetaOperator::Regex),
+                _ => None,
             };
-			// We have to combine parts 1 and 2 when a colon exists in the regex
-			let res = if operator == MetaOperator::Regex { search[parts[0].len()+1..].to_string() } else { parts[2].to_string() };
-            Ok(MetaSearch {
-                field: parts[0].to_string(),
-                operator,
-                pattern: res,
-            })
+			let op_start = parts[0].len() + 1;
+			let value_start = op_start + parts[1].len() + 1;
+			match operator {
+				Some(operator) => MetaSearch::new(parts[0], operator, case_insensitive, parts[2]).map_err(|(part, e)| match part {
+					MetaPart::Field => (0..parts[0].len(), e),
+					MetaPart::Operator => (op_start..value_start - 1, e),
+					MetaPart::Value => (value_start..search.len(), e),
+				}),
+				// Not an operator: we have to combine parts 1 and 2 as the colon belongs to the regex
+				None => MetaSearch::new(parts[0], MetaOperator::Regex, false, &search[op_start..]).map_err(|(part, e)| match part {
+					MetaPart::Field => (0..parts[0].len(), e),
+					_ => (op_start..search.len(), e),
+				}),
+			}
         }
-        _ => Err("Invalid meta search format".to_string()),
+        _ => Err((0..search.len(), "expected \"field:pattern\" or \"field:operator:pattern\"".to_string())),
     }
 }
 
+// The regex crate's syntax errors repeat the pattern with a caret over several lines, keep only the reason
+fn regex_error(e: &regex::Error) -> String {
+	let e = e.to_string();
+	e.lines().last().unwrap_or("").trim_start_matches("error: ").to_string()
+}
+
+// Parse every -m/-M argument up front, pointing at the argument and part that's wrong
+fn parse_meta_searches(flag: &str, searches: &[String]) -> Result<Vec<MetaSearch>> {
+	searches.iter().enumerate().map(|(i, search)| parse_meta_search(search).map_err(|(span, e)| {
+		let column = search[..span.start].chars().count();
+		let width = search[span].chars().count().max(1);
+		anyhow::anyhow!("Invalid {} argument #{} \"{}\": {}\n  {}\n  {}{}", flag, i + 1, search, e, search, " ".repeat(column), "^".repeat(width))
+	})).collect()
+}
+
+// Compare two ordered values with a MetaOperator
+fn compare<T: PartialOrd>(a: T, op: MetaOperator, b: T) -> bool {
+	match op {
+		MetaOperator::Equals => a == b,
+		MetaOperator::NotEquals => a != b,
+		MetaOperator::GreaterThan => a > b,
+		MetaOperator::GreaterEq => a >= b,
+		MetaOperator::LessThan => a < b,
+		MetaOperator::LessEq => a <= b,
+		MetaOperator::Regex => false,
+	}
+}
+
 // Metadata matching function
 fn matches_metadata(meta: &fs::Metadata, search: &MetaSearch) -> bool {
-    match search.field.as_str() {
-        "size" => {
-            let size = meta.len();
-            match search.operator {
-                MetaOperator::Equals => size.to_string().contains(&search.pattern),
-                MetaOperator::GreaterThan => {
-                    search.pattern.parse::<u64>().map_or(false, |min_size| size >= min_size)
-                }
-                MetaOperator::LessThan => {
-                    search.pattern.parse::<u64>().map_or(false, |max_size| size <= max_size)
-                }
-                MetaOperator::Contains | MetaOperator::Regex => {
-                    let regex = Regex::new(&search.pattern).unwrap_or_else(|_| Regex::new(".*").unwrap());
-                    regex.is_match(&size.to_string())
-                }
-            }
-        }
-		// Search on "metadata"
-        "modified" | "created" | "accessed"| "readonly" => {
-            let res = match search.field.as_str() {
-                "modified" => to_dt!(meta.modified()),
-                "created" => to_dt!(meta.created()),
-                "accessed" => to_dt!(meta.accessed()),
-				"readonly" => meta.permissions().readonly().to_string(),
-                _ => return false,
-            };
-            match search.operator {
-				MetaOperator::Contains | MetaOperator::Regex => {
-					let regex = Regex::new(&search.pattern).unwrap_or_else(|_| Regex::new(".*").unwrap());
-					regex.is_match(&res)
+	let op = search.operator;
+	match (search.field.as_str(), &search.value) {
+		("size", MetaValue::Size(size)) => compare(meta.len(), op, *size),
+		("size", MetaValue::Regex(re)) => re.is_match(&meta.len().to_string()),
+
+		("readonly", MetaValue::Bool(b)) => compare(meta.permissions().readonly(), op, *b),
+		("readonly", MetaValue::Regex(re)) => re.is_match(&meta.permissions().readonly().to_string()),
+
+		// Temporal comparisons: "<:7d" (newer than 7 days), ">:2025-01-01" (after that day), "2025-01..2025-03" (a range)
+		(field, value) => {
+			let time = match field {
+				"modified" => meta.modified(),
+				"created" => meta.created(),
+				_ => meta.accessed(),
+			};
+			let Ok(time) = time else { return false };
+			match value {
+				MetaValue::Regex(re) => re.is_match(&to_dt!(Ok::<SystemTime, ()>(time))),
+				MetaValue::TimeRange(from, to) => {
+					let within = from.is_none_or(|f| time >= f) && to.is_none_or(|t| time < t);
+					if op == MetaOperator::NotEquals { !within } else { within }
+				}
+				MetaValue::Time(spec) => match op {
+					MetaOperator::LessThan => time_less(time, *spec),
+					MetaOperator::LessEq => time_less(time, *spec) || time_equals(time, *spec),
+					MetaOperator::GreaterThan => time_greater(time, *spec),
+					MetaOperator::GreaterEq => time_greater(time, *spec) || time_equals(time, *spec),
+					MetaOperator::Equals => time_equals(time, *spec),
+					MetaOperator::NotEquals => !time_equals(time, *spec),
+					MetaOperator::Regex => false,
 				},
-				MetaOperator::Equals => {
-					res == search.pattern
-				},
-				_ => res.contains(&search.pattern),
+				_ => false,
 			}
-        }
-        _ => false, // Unknown field
-    }
+		}
+	}
 }
 
 
 use std::collections::HashMap;
 
+// Every %token% of the format strings and --columns, the keys get_file_format_values and get_dir_format_values fill in.
+// Files leave the directory only ones empty and neither has the time ones when it couldn't be stat'ed
+const FORMAT_TOKENS: &[&str] = &["name", "path", "full_path", "size", "alloc_size", "apparent_size", "immediate_files_size", "total_size",
+	"total_files", "total_dirs", "p_immediate_files_size", "p_total_size", "p_total_files", "p_total_dirs", "immediate_files_apparent_size",
+	"total_apparent_size", "p_immediate_files_apparent_size", "p_total_apparent_size", "sub_dirs_count", "sub_files_count", "depth",
+	"modified", "created", "accessed", "is_file", "is_dir", "readonly", "hash", "error"];
+
+// The --help list of FORMAT_TOKENS
+fn token_help() -> String {
+	let mut help = String::from("Usable %token%s:");
+	let mut width = help.len();
+	for (i, token) in FORMAT_TOKENS.iter().enumerate() {
+		let token = if i + 1 < FORMAT_TOKENS.len() { format!("{},", token) } else { token.to_string() };
+		if width + 1 + token.len() > 110 { help.push_str("\n\t"); width = 8; } else { help.push(' '); width += 1; }
+		help.push_str(&token);
+		width += token.len();
+	}
+	help
+}
+
 // Format a string by replacing placeholders with actual values
 fn format_string(format_str: &str, values: &HashMap<&str, String>, is_dir: bool) -> String {
     let mut result = format_str.to_string();
@@ -244,14 +422,23 @@ fn format_string(format_str: &str, values: &HashMap<&str, String>, is_dir: bool)
 }
 
 // Get available format values for a file
-fn get_file_format_values(file: &FileInfo, metadata: &fs::Metadata) -> HashMap<&'static str, String> {
+fn get_file_format_values(file: &FileInfo, args: &Args) -> HashMap<&'static str, String> {
     let mut values = HashMap::new();
 
     values.insert("name", file.name.clone());
-    values.insert("size", file.size.to_string());
+    values.insert("size", fmt_size(file.size(args.apparent_size), args));
+	values.insert("alloc_size", fmt_size(file.size, args));
+	values.insert("apparent_size", fmt_size(file.apparent_size, args));
     values.insert("path", file.path.to_string_lossy().to_string());
+	values.insert("full_path", std::path::absolute(&file.path).unwrap_or_else(|_| file.path.clone()).to_string_lossy().to_string());
+	values.insert("hash", file.hash.get().map(|h| format!("{:016x}", h)).unwrap_or_default());
+	values.insert("error", file.error.clone().unwrap_or_default());
+
+    values.insert("is_file", "true".to_string());
+    values.insert("is_dir", "false".to_string());
 
-    // Add metadata values
+    // Add metadata values, left out when the entry couldn't be stat'ed
+    let Some(metadata) = &file.metadata else { return values };
     if let Ok(modified) = metadata.modified() {
         values.insert("modified", to_dt!(Some(modified)));
     }
@@ -262,35 +449,45 @@ fn get_file_format_values(file: &FileInfo, metadata: &fs::Metadata) -> HashMap<&
         values.insert("accessed", to_dt!(Some(accessed)));
     }
 
-    values.insert("is_file", "true".to_string());
-    values.insert("is_dir", "false".to_string());
     values.insert("readonly", metadata.permissions().readonly().to_string());
 
     values
 }
 
 // Get available format values for a directory
-fn get_dir_format_values(dir: &DirInfo, metadata: &fs::Metadata) -> HashMap<&'static str, String> {
+fn get_dir_format_values(dir: &DirInfo, args: &Args) -> HashMap<&'static str, String> {
     let mut values = HashMap::new();
 
     values.insert("name", dir.name.clone());
-	values.insert("size", dir.total_size.to_string());
+	values.insert("size", fmt_size(dir.total_size(args.apparent_size), args));
+	values.insert("alloc_size", fmt_size(dir.total_size, args));
+	values.insert("apparent_size", fmt_size(dir.total_apparent_size, args));
     values.insert("path", dir.path.to_string_lossy().to_string());
+	values.insert("full_path", std::path::absolute(&dir.path).unwrap_or_else(|_| dir.path.clone()).to_string_lossy().to_string());
 
     // Add directory statistics
-    values.insert("immediate_files_size", dir.immediate_files_size.to_string());
-	values.insert("total_size", dir.total_size.to_string());
+    values.insert("immediate_files_size", fmt_size(dir.immediate_files_size(args.apparent_size), args));
+	values.insert("total_size", fmt_size(dir.total_size(args.apparent_size), args));
     values.insert("total_files", dir.total_files.to_string());
     values.insert("total_dirs", dir.total_dirs.to_string());
-    values.insert("p_immediate_files_size", dir.p_immediate_files_size.to_string());
-    values.insert("p_total_size", dir.p_total_size.to_string());
+    values.insert("p_immediate_files_size", fmt_size(dir.p_immediate_files_size(args.apparent_size), args));
+    values.insert("p_total_size", fmt_size(dir.p_total_size(args.apparent_size), args));
     values.insert("p_total_files", dir.p_total_files.to_string());
     values.insert("p_total_dirs", dir.p_total_dirs.to_string());
+	values.insert("immediate_files_apparent_size", fmt_size(dir.immediate_files_apparent_size, args));
+	values.insert("total_apparent_size", fmt_size(dir.total_apparent_size, args));
+	values.insert("p_immediate_files_apparent_size", fmt_size(dir.p_immediate_files_apparent_size, args));
+	values.insert("p_total_apparent_size", fmt_size(dir.p_total_apparent_size, args));
     values.insert("sub_dirs_count", dir.sub_dirs.len().to_string());
     values.insert("sub_files_count", dir.sub_files.len().to_string());
     values.insert("depth", dir.depth.to_string());
+	values.insert("error", dir.error.clone().unwrap_or_default());
 
-    // Add metadata values
+    values.insert("is_file", "false".to_string());
+    values.insert("is_dir", "true".to_string());
+
+    // Add metadata values, left out when the entry couldn't be stat'ed
+    let Some(metadata) = &dir.metadata else { return values };
     if let Ok(modified) = metadata.modified() {
         values.insert("modified", to_dt!(Some(modified)));
     }
@@ -301,8 +498,6 @@ fn get_dir_format_values(dir: &DirInfo, metadata: &fs::Metadata) -> HashMap<&'st
         values.insert("accessed", to_dt!(Some(accessed)));
     }
 
-    values.insert("is_file", "false".to_string());
-    values.insert("is_dir", "true".to_string());
     values.insert("readonly", metadata.permissions().readonly().to_string());
 
     values
@@ -314,6 +509,17 @@ fn get_dir_format_values(dir: &DirInfo, metadata: &fs::Metadata) -> HashMap<&'st
 const DEFAULT_FILE_FORMAT: &str = " (size = %size%, created %created%, accessed %accessed%, modified %modified%)";
 const DEFAULT_DIR_FORMAT: &str = " (size = %p_total_size%/%total_size%, dirs = %sub_dirs_count%/%p_total_dirs%, files = %sub_files_count%/%p_total_files%)";
 
+// Render a size token in the units selected by --human/--si
+fn fmt_size(bytes: u64, args: &Args) -> String {
+	let units = if args.si { SizeUnits::Si } else if args.human { SizeUnits::Binary } else { SizeUnits::Bytes };
+	format_size(bytes, units, args.precision)
+}
+
+// The summary says "bytes" when sizes aren't scaled
+fn fmt_size_summary(bytes: u64, args: &Args) -> String {
+	if args.human || args.si { fmt_size(bytes, args) } else { format!("{} bytes", bytes) }
+}
+
 // Helper to get the appropriate default format
 fn get_format_string(args: &Args, is_dir: bool) -> String {
 	let e = match std::env::var(if is_dir { "TREEE_FORMAT_DIR" } else { "TREEE_FORMAT_FILE"}) { Ok(v) => { v }, Err(v) => {"".to_string()} };
@@ -321,14 +527,14 @@ fn get_format_string(args: &Args, is_dir: bool) -> String {
 	let e = e.replace("^%", "%");
 
 	if is_dir {
-	    match &args.print_formatP {
+	    match &args.print_format_dir {
 			Some(Some(format_str)) => format_str.clone(), // -p "custom" used
 			Some(None) => if is_dir {DEFAULT_DIR_FORMAT.to_string()} else {DEFAULT_FILE_FORMAT.to_string()},
 			None => e
 		}
 	}
 	else {
-   		match &args.print_formatp {
+   		match &args.print_format_file {
 			Some(Some(format_str)) => format_str.clone(), // -p "custom" used
 			Some(None) => if is_dir {DEFAULT_DIR_FORMAT.to_string()} else {DEFAULT_FILE_FORMAT.to_string()},
 			None => e
@@ -341,9 +547,15 @@ fn get_format_string(args: &Args, is_dir: bool) -> String {
 struct FileInfo {
 	name: String,
 	path: PathBuf,
-    size: u64,
+    size: u64,			// Allocated size on disk
+	apparent_size: u64,	// Logical size (metadata.len())
 	regex_matched: bool,
-	parent: ParentRef<DirInfo>,
+	ignored: bool,		// matched an ignore file, only kept with --show-ignored
+	hash: Cell<Option<u64>>,	// content hash, only computed for --dupes and %hash%
+	metadata: Option<fs::Metadata>,	// from the walk, None if the entry couldn't be stat'ed
+	error: Option<String>,	// why the entry couldn't be stat'ed
+	matches: Vec<bool>,	// per predicate results of the file matcher, for coloring
+	parent: DirId,
 }
 
 #[derive(Debug)]
@@ -354,6 +566,8 @@ struct DirInfo {
     immediate_files_size: u64,
 
     total_size: u64,	// Total size including all subdirectories
+	immediate_files_apparent_size: u64,	// apparent size twins of the above
+	total_apparent_size: u64,
 	total_files: u64,	// Total files
 	total_dirs: u64,	// Total dirs
 
@@ -361,6 +575,8 @@ struct DirInfo {
 	// statistics after parsing	of matched files and dirs
 	p_immediate_files_size: u64,	// sum of immediate matched file sizes
 	p_total_size: u64,				// sum of all matched file sizes
+	p_immediate_files_apparent_size: u64,
+	p_total_apparent_size: u64,
 	p_total_files: u64,				// total number of matched files found
 	p_total_dirs: u64,				// total number of matched dirs
 
@@ -369,10 +585,129 @@ struct DirInfo {
 	contains_dir_matching_regex: bool,
 	contains_file_matching_regex: bool,
 	contains_meta_matching_regex: bool,
+	pruned: bool,		// --prune-dirs skipped this directory's contents
+	excluded: bool,		// matched an exclusion, hidden and its contents not walked
+	ignored: bool,		// this directory or an ancestor matched an ignore file, only kept with --show-ignored
+	pruned_dirs: u64,	// number of pruned directories in this subtree
+	error: Option<String>,	// why the directory (or some of its entries) couldn't be read
+	total_errors: u64,	// number of entries in this subtree, itself included, that couldn't be read
 	depth: usize,
-	parent: ParentRef<DirInfo>,
-	sub_dirs: Vec<Rc<RefCell<DirInfo>>>,
-	sub_files: Vec<Rc<RefCell<FileInfo>>>,
+	metadata: Option<fs::Metadata>,	// from the walk, None if the directory couldn't be stat'ed
+	matches: Vec<bool>,	// per predicate results of the dir matcher, for coloring and --prune-dirs
+	parent: Option<DirId>,
+	sub_dirs: Range<DirId>,		// ids in Tree::dirs
+	sub_files: Range<FileId>,	// ids in Tree::files
+}
+
+impl FileInfo {
+	/// The size selected by --apparent-size
+	fn size(&self, apparent: bool) -> u64 { if apparent { self.apparent_size } else { self.size } }
+}
+
+impl DirInfo {
+	/// A directory before it's been read, with no children or statistics
+	fn new(path: &Path, depth: usize, parent: Option<DirId>, ignored: bool, metadata: Option<fs::Metadata>) -> DirInfo {
+		DirInfo {
+			path: path.to_path_buf(),
+			name: path.file_name().unwrap_or_default().to_string_lossy().to_string(),
+			depth,
+			metadata,
+			matches: Vec::new(),
+			regex_matched: false,
+			parent,
+
+			// total data
+			immediate_files_size: 0,
+			total_size: 0,
+			immediate_files_apparent_size: 0,
+			total_apparent_size: 0,
+			total_files: 0,
+			total_dirs: 0,
+
+			// parsed data,
+			p_immediate_files_size: 0,
+			p_total_size: 0,
+			p_immediate_files_apparent_size: 0,
+			p_total_apparent_size: 0,
+			p_total_files: 0,
+			p_total_dirs: 0,
+
+			sub_dirs: 0..0,
+			sub_files: 0..0,
+			contains_dir_matching_regex: false,
+			contains_file_matching_regex: false,
+			contains_meta_matching_regex: false,
+			pruned: false,
+			pruned_dirs: 0,
+			error: None,
+			total_errors: 0,
+			excluded: false,
+			ignored,
+		}
+	}
+
+	/// The sizes selected by --apparent-size
+	fn immediate_files_size(&self, apparent: bool) -> u64 { if apparent { self.immediate_files_apparent_size } else { self.immediate_files_size } }
+	fn p_immediate_files_size(&self, apparent: bool) -> u64 { if apparent { self.p_immediate_files_apparent_size } else { self.p_immediate_files_size } }
+	fn total_size(&self, apparent: bool) -> u64 { if apparent { self.total_apparent_size } else { self.total_size } }
+	fn p_total_size(&self, apparent: bool) -> u64 { if apparent { self.p_total_apparent_size } else { self.p_total_size } }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+enum OutputMode {
+	/// The colored tree
+	Tree,
+	/// The filtered tree as a single JSON document
+	Json,
+	/// One JSON object per entry in tree order, "matched" tells the matches from the directories leading to them
+	Ndjson,
+	/// One row per matched entry with the --columns tokens
+	Csv,
+	/// Like csv but tab separated
+	Tsv,
+	/// A self contained html report with a collapsible tree
+	Html,
+	/// The tree as markdown, see --md-style
+	Markdown,
+	/// XML like GNU tree -X
+	Xml,
+	/// JSON like GNU tree -J
+	TreeJson,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+enum MarkdownStyle {
+	/// The tree inside a fenced code block
+	Fence,
+	/// A nested bullet list, names can be links with --md-links
+	List,
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+enum Order {
+	FilesFirst,
+	DirsFirst,
+	/// Files and directories merged by the sort key
+	Mixed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+enum SortBy {
+	Name,
+	/// File size, or the total size for directories (not known with --stream, which only sorts the files by size)
+	Size,
+	#[value(name = "total_size")]
+	TotalSize,
+	#[value(name = "p_total_size")]
+	PTotalSize,
+	Mtime,
+	Ctime,
+	Atime,
+	Ext,
+	/// Number of entries below a directory
+	Count,
 }
 
 
@@ -380,7 +715,7 @@ struct DirInfo {
 #[command(name = "tree")]
 #[command(about = "A tree command with regex filtering")]
 #[command(version = "1.0.0")]
-#[command(after_help = "Usable %token%s: path, full_path, immediate_files_size, total_size, total_files, total_dirs, \n\tp_immediate_files_size, p_total_size, p_total_files, p_total_dirs, \n\tsub_dirs_count, sub_files_count, depth, modified, created, accessed, is_dir, readonly")]
+#[command(after_help = token_help())]
 struct Args {
     /// Directory to traverse
     #[arg(default_value = ".")]
@@ -394,6 +729,11 @@ struct Args {
     #[arg(short = 'H', long)]
     no_hidden: bool,
 
+	/// Boolean filter expression, e.g. "(ext is rs OR ext is toml) AND size > 10000 AND NOT name ~ test".
+	/// Fields: name, path, ext, type, size, modified, created, accessed, readonly. Ops: ~ ^~ = is ^= != > >= < <=
+	#[arg(short = 'w', long = "where", value_name = "EXPR")]
+	where_expr: Option<String>,
+
 	/// Intersect all matches
 	#[arg(short = 'a', long = "all", default_value_t = false)]
     all: bool,
@@ -406,6 +746,38 @@ struct Args {
     #[arg(short = 'S', long = "summary", default_value_t = false)]
     summary: bool,
 
+    /// Report the N largest matched files and/or directories with their share of the total size
+    #[arg(long = "top", value_name = "N")]
+    top: Option<usize>,
+
+    /// Which entries --top reports
+    #[arg(long = "top-kind", value_name = "KIND", value_enum, default_value_t = TopKind::Both)]
+    top_kind: TopKind,
+
+    /// Print only the --top report, without the tree
+    #[arg(long = "top-only", default_value_t = false)]
+    top_only: bool,
+
+    /// Add a table of the matched files grouped by extension and/or category to the summary (implies -S)
+    #[arg(long = "breakdown", value_enum, value_delimiter = ',')]
+    breakdown: Vec<Breakdown>,
+
+    /// Find matched files with identical contents and report them with the bytes they waste
+    #[arg(long = "dupes", default_value_t = false)]
+    dupes: bool,
+
+    /// Threads reading directories ahead of the walk, 0 for one per CPU. The tree is still built in one pass so the output is the same whatever the count
+    #[arg(long = "threads", value_name = "N", default_value_t = 1)]
+    threads: usize,
+
+    /// Print matched entries as flat paths as soon as they're found, without holding the tree. Subtree totals aren't available so --sort size only orders files
+    #[arg(long = "stream", default_value_t = false)]
+    stream: bool,
+
+    /// Exit with an error after printing if any entry couldn't be read
+    #[arg(long = "strict", default_value_t = false)]
+    strict: bool,
+
     /// Regular expression to filter file names (default: ".*")
     #[arg(short = 'f', long = "file-regex", value_name = "PATTERN", default_value = "")]
     file_regex: Vec<String>,
@@ -423,16 +795,104 @@ struct Args {
     dir_regex_c: Vec<String>,
 
 
-	/// Regular expression to filter by metadata (format: "field:pattern", e.g., "size:>1024", "modified:.*2023.*")
+	/// Filter by metadata (format: "field:op:value" or "field:regex", e.g., "size:>:1024", "modified:.*2023.*")
     #[arg(short = 'm', long = "meta-search", value_name = "FIELD:PATTERN")]
     meta_search: Vec<String>,
 
-    /// Prune directory traversal: skip directories whose names don't match.
+    /// Don't honor .gitignore, .ignore, .git/info/exclude and git's global excludes file, and show .git directories
+    #[arg(long = "no-ignore", default_value_t = false)]
+    no_ignore: bool,
+
+    /// Show entries matched by ignore files dimmed instead of hiding them
+    #[arg(long = "show-ignored", default_value_t = false)]
+    show_ignored: bool,
+
+    /// Use apparent sizes (metadata length) instead of allocated disk usage for %size%, %immediate_files_size%, %total_size%, %p_immediate_files_size%, %p_total_size% and the summary
+    #[arg(long = "apparent-size", default_value_t = false)]
+    apparent_size: bool,
+
+    /// Regular expression for file names to exclude (anti-match of -f)
+    #[arg(long = "exclude-file", value_name = "PATTERN")]
+    exclude_file: Vec<String>,
+
+    /// Regular expression for directory names to exclude along with their contents (anti-match of -d). Excluded directories aren't walked so their contents aren't in any totals
+    #[arg(long = "exclude-dir", value_name = "PATTERN")]
+    exclude_dir: Vec<String>,
+
+    /// The case sensitive version of --exclude-file
+    #[arg(long = "exclude-file-c", value_name = "PATTERN")]
+    exclude_file_c: Vec<String>,
+
+    /// The case sensitive version of --exclude-dir
+    #[arg(long = "exclude-dir-c", value_name = "PATTERN")]
+    exclude_dir_c: Vec<String>,
+
+	/// Exclude entries matching a metadata search (anti-match of -m, same format)
+    #[arg(short = 'M', long = "exclude-meta", value_name = "FIELD:PATTERN")]
+    exclude_meta: Vec<String>,
+
+    /// Show sizes in powers of 1024 (KiB, MiB, ...)
+    #[arg(long = "human", default_value_t = false)]
+    human: bool,
+
+    /// Show sizes in powers of 1000 (kB, MB, ...)
+    #[arg(long = "si", default_value_t = false, conflicts_with = "human")]
+    si: bool,
+
+    /// Number of decimals for --human and --si sizes
+    #[arg(long = "precision", value_name = "N", default_value_t = 1)]
+    precision: usize,
+
+    /// Prune directory traversal: skip directories whose names don't match (-d/-D and non size -m predicates).
     #[arg(long)]
     prune_dirs: bool,
 
 
 
+    /// Sort files and directories by. Sizes, counts and times sort largest/newest first
+    #[arg(long = "sort", value_enum, default_value_t = SortBy::Name)]
+    sort: SortBy,
+
+    /// Reverse the sort order
+    #[arg(short = 'r', long = "reverse", default_value_t = false)]
+    reverse: bool,
+
+    /// Compare numbers in names by value (file2 before file10, v1.9 before v1.10)
+    #[arg(long = "natural", default_value_t = false)]
+    natural: bool,
+
+    /// Ignore case when sorting names
+    #[arg(long = "ignore-case", default_value_t = false)]
+    ignore_case: bool,
+
+    /// Where files go relative to directories
+    #[arg(long = "order", value_enum, default_value_t = Order::FilesFirst)]
+    order: Order,
+
+    /// Output format
+    #[arg(short = 'o', long = "output", value_enum, default_value_t = OutputMode::Tree)]
+    output: OutputMode,
+
+    /// Comma separated %token% names to output as columns for csv and tsv
+    #[arg(long = "columns", value_name = "TOKENS", value_delimiter = ',', default_value = "path,is_dir,size,modified")]
+    columns: Vec<String>,
+
+    /// Same as --output xml, like GNU tree
+    #[arg(short = 'X', default_value_t = false)]
+    xml: bool,
+
+    /// Same as --output tree-json, like GNU tree
+    #[arg(short = 'J', default_value_t = false, conflicts_with = "xml")]
+    tree_json: bool,
+
+    /// How --output markdown renders the tree
+    #[arg(long = "md-style", value_enum, default_value_t = MarkdownStyle::Fence)]
+    md_style: MarkdownStyle,
+
+    /// Link names to their path relative to the root in --md-style list
+    #[arg(long = "md-links", default_value_t = false)]
+    md_links: bool,
+
     /// Print full paths instead of the tree format
     #[arg(short = 'i', long = "flat", default_value_t = false)]
     no_indent: bool,
@@ -440,11 +900,11 @@ struct Args {
 
 	/// Format string for file output(or use TREEE_FORMAT_FILE env) (e.g., "", "size=%size%, creation=%creation%")
     #[arg(short = 'p', long = "print-format", value_name = "FORMAT", required=false)]
-    print_formatp: Option<Option<String>>,
+    print_format_file: Option<Option<String>>,
 
 	/// Format string for dir output(or use TREEE_FORMAT_DIR env) (e.g., "", "size=%size%, creation=%creation%")
-    #[arg(short = 'P', long = "print-format", value_name = "FORMAT", required=false)]
-    print_formatP: Option<Option<String>>,
+    #[arg(short = 'P', long = "print-dir-format", value_name = "FORMAT", required=false)]
+    print_format_dir: Option<Option<String>>,
 
 }
 
@@ -461,10 +921,27 @@ struct Args {
 
 
 fn main() -> Result<()> {
-    let args = Args::parse();
-    if !args.path.exists() {
+    let mut args = Args::parse();
+	if args.xml { args.output = OutputMode::Xml; }
+	if args.tree_json { args.output = OutputMode::TreeJson; }
+
+	if !args.breakdown.is_empty() { args.summary = true; }
+
+	if args.top == Some(0) { anyhow::bail!("--top needs a count of at least 1"); }
+	let top = args.top;
+	if top.is_some() && args.output != OutputMode::Tree { anyhow::bail!("--top only works with the tree output"); }
+	if args.top_only && top.is_none() { anyhow::bail!("--top-only needs --top N"); }
+	if matches!(args.output, OutputMode::Csv | OutputMode::Tsv) { output::csv::check_columns(&args)?; }
+
+	// Everything that needs the whole tree
+	if args.stream {
+		if args.output != OutputMode::Tree { anyhow::bail!("--stream only works with the tree output"); }
+		if top.is_some() || args.dupes || !args.breakdown.is_empty() { anyhow::bail!("--stream can't be used with --top, --dupes or --breakdown"); }
+		if matches!(args.sort, SortBy::TotalSize | SortBy::PTotalSize | SortBy::Count) { anyhow::bail!("--stream can't sort by directory totals"); }
+	}
+    let Ok(root_metadata) = fs::metadata(&args.path) else {
         anyhow::bail!("Path '{}' does not exist or is not accessible.", args.path.display());
-    }
+    };
 
 
 
@@ -476,11 +953,14 @@ fn main() -> Result<()> {
 
 	// handle meta search
 	let meta_specified = !args.meta_search.is_empty();
-	let meta_searches: Vec<MetaSearch> = args.meta_search.clone().iter().filter_map(|s| parse_meta_search(s).ok()).collect::<Vec<MetaSearch>>();
+	let meta_searches: Vec<MetaSearch> = parse_meta_searches("-m/--meta-search", &args.meta_search)?;
+	// A directory's size is only known after walking it, so size predicates can't be used to prune
+	let prune_meta: Vec<bool> = meta_searches.iter().map(|ms| ms.field != "size").collect();
 
-	let meta_matcher = move  |m: &fs::Metadata| -> (bool, Vec<bool>) {
+	// An entry that couldn't be stat'ed matches no metadata predicate
+	let meta_matcher = move  |m: Option<&fs::Metadata>| -> (bool, Vec<bool>) {
 		if !meta_specified { return (true, vec![]) }
-		let mut matches: Vec<bool> = meta_searches.iter().map(|ms| matches_metadata(m,ms)).collect();
+		let mut matches: Vec<bool> = meta_searches.iter().map(|ms| m.is_some_and(|m| matches_metadata(m,ms))).collect();
 		if args.all { return (matches.iter().all(|&m| m), matches) }
 		(matches.iter().any(|&m| m), matches)
     };
@@ -496,82 +976,157 @@ fn main() -> Result<()> {
     let dirs_specified = !dir_regex.is_empty() || !dir_regex_c.is_empty();
 
 
-	let file_regexes: Result<Vec<Regex>, _> = file_regex.iter().map(|r| Regex::new( &r)).collect();
+	let file_regexes: Result<Vec<Regex>, _> = file_regex.iter().map(|r| Regex::new(r)).collect();
 	let file_regexes = file_regexes?;
-	let dir_regexes: Result<Vec<Regex>, _> = dir_regex.iter().map(|r| Regex::new( &r)).collect();
+	let dir_regexes: Result<Vec<Regex>, _> = dir_regex.iter().map(|r| Regex::new(r)).collect();
 	let dir_regexes = dir_regexes?;
 
-	let file_regexes_c: Result<Vec<Regex>, _> = file_regex_c.iter().map(|r| Regex::new(&r)).collect();
+	let file_regexes_c: Result<Vec<Regex>, _> = file_regex_c.iter().map(|r| Regex::new(r)).collect();
 	let file_regexes_c = file_regexes_c?;
-	let dir_regexes_c: Result<Vec<Regex>, _> = dir_regex_c.iter().map(|r| Regex::new(&r)).collect();
+	let dir_regexes_c: Result<Vec<Regex>, _> = dir_regex_c.iter().map(|r| Regex::new(r)).collect();
 	let dir_regexes_c = dir_regexes_c?;
 
+	// The --where expression is ANDed with the -f/-d/-m matches, its terms are appended to the match vector for coloring
+	let where_filter: Option<FilterExpr> = args.where_expr.as_ref().map(|w| FilterExpr::parse(w)).transpose()?;
+	let where_specified = where_filter.is_some();
+
 	let file_matcher = |file: &FileInfo| -> (bool, Vec<bool>) {
 		let name = file.name.clone();
-		let metadata = &fs::metadata(file.path.clone()).ok().expect("");
+		let metadata = file.metadata.as_ref();
 		if name.is_empty() { return (false, vec![]); }
-		if !files_specified && !meta_specified { return (!dirs_specified, vec![]); }
-		let mut matches: Vec<bool> = file_regexes.iter().map(|re| if files_specified { re.is_match(&name.to_lowercase()) } else { !dirs_specified }).collect();
-		let mut matches_c: Vec<bool> = file_regexes_c.iter().map(|re| if files_specified { re.is_match(&name) } else { !dirs_specified }).collect();
-		matches.append(&mut matches_c);
-		if meta_specified { matches.append(&mut meta_matcher(metadata).1) }
-		if args.all { return (matches.iter().all(|&m| m), matches) }
-		(matches.iter().any(|&m| m), matches)
+		let (matched, mut matches) = if !files_specified && !meta_specified { (!dirs_specified, vec![]) } else {
+			let mut matches: Vec<bool> = file_regexes.iter().map(|re| if files_specified { re.is_match(&name.to_lowercase()) } else { !dirs_specified }).collect();
+			let mut matches_c: Vec<bool> = file_regexes_c.iter().map(|re| if files_specified { re.is_match(&name) } else { !dirs_specified }).collect();
+			matches.append(&mut matches_c);
+			if meta_specified { matches.append(&mut meta_matcher(metadata).1) }
+			if args.all { (matches.iter().all(|&m| m), matches) } else { (matches.iter().any(|&m| m), matches) }
+		};
+		let Some(w) = &where_filter else { return (matched, matches) };
+		let (wm, mut terms) = w.eval(&name, &file.path, false, metadata);
+		matches.append(&mut terms);
+		(matched && wm, matches)
 	};
 
 	let dir_matcher = |dir: &DirInfo| -> (bool, Vec<bool>) {
 		let name = dir.name.clone();
-		let metadata = &fs::metadata(dir.path.clone()).ok().expect("");
+		let metadata = dir.metadata.as_ref();
 		if name.is_empty() { return (false, vec![]); }
-		if !dirs_specified && !meta_specified { return (!files_specified, vec![]); }
-		let mut matches: Vec<bool> = dir_regexes.iter().map(|re| if dirs_specified { re.is_match(&name.to_lowercase()) } else { !files_specified }).collect();
-		let mut matches_c: Vec<bool> = dir_regexes_c.iter().map(|re| if dirs_specified { re.is_match(&name) } else { !files_specified }).collect();
-		matches.append(&mut matches_c);
-		if meta_specified { matches.append(&mut meta_matcher(&metadata).1) }
-		if args.all { return (matches.iter().all(|&m| m), matches) }
-		(matches.iter().any(|&m| m), matches)
+		let (matched, mut matches) = if !dirs_specified && !meta_specified { (!files_specified, vec![]) } else {
+			let mut matches: Vec<bool> = dir_regexes.iter().map(|re| if dirs_specified { re.is_match(&name.to_lowercase()) } else { !files_specified }).collect();
+			let mut matches_c: Vec<bool> = dir_regexes_c.iter().map(|re| if dirs_specified { re.is_match(&name) } else { !files_specified }).collect();
+			matches.append(&mut matches_c);
+			if meta_specified { matches.append(&mut meta_matcher(metadata).1) }
+			if args.all { (matches.iter().all(|&m| m), matches) } else { (matches.iter().any(|&m| m), matches) }
+		};
+		let Some(w) = &where_filter else { return (matched, matches) };
+		let (wm, mut terms) = w.eval(&name, &dir.path, true, metadata);
+		matches.append(&mut terms);
+		(matched && wm, matches)
+	};
+
+	// Decides whether --prune-dirs descends into a directory, from the match vector the dir matcher left on it. Only -d/-D
+	// and directory applicable -m predicates are used
+	let prune_mask: Vec<bool> = vec![true; dir_regexes.len() + dir_regexes_c.len()].into_iter().chain(prune_meta.clone()).collect();
+	let prune_matcher = |dir: &DirInfo| -> bool {
+		if !dirs_specified && !prune_meta.iter().any(|&m| m) { return true; }
+		let matches: Vec<bool> = dir.matches.iter().zip(prune_mask.iter()).filter(|&(_, &m)| m).map(|(&v, _)| v).collect();
+		if args.all { return matches.iter().all(|&m| m) }
+		matches.iter().any(|&m| m)
+	};
+
+
+	// Build the exclusion closures. Anything matching one of these is never matched, excluded dirs hide their whole subtree
+	let exclude_file: Vec<Regex> = args.exclude_file.iter().map(|s| s.trim()).filter(|s| !s.is_empty()).map(|r| RegexBuilder::new(r).case_insensitive(true).build()).collect::<Result<_, _>>()?;
+	let exclude_dir: Vec<Regex> = args.exclude_dir.iter().map(|s| s.trim()).filter(|s| !s.is_empty()).map(|r| RegexBuilder::new(r).case_insensitive(true).build()).collect::<Result<_, _>>()?;
+	let exclude_file_c: Vec<Regex> = args.exclude_file_c.iter().map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).map(|r| Regex::new(&r)).collect::<Result<_, _>>()?;
+	let exclude_dir_c: Vec<Regex> = args.exclude_dir_c.iter().map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).map(|r| Regex::new(&r)).collect::<Result<_, _>>()?;
+	let exclude_meta: Vec<MetaSearch> = parse_meta_searches("-M/--exclude-meta", &args.exclude_meta)?;
+
+	let file_excluder = |file: &FileInfo| -> bool {
+		if exclude_file.iter().any(|re| re.is_match(&file.name)) || exclude_file_c.iter().any(|re| re.is_match(&file.name)) { return true; }
+		if exclude_meta.is_empty() { return false; }
+		file.metadata.as_ref().is_some_and(|m| exclude_meta.iter().any(|ms| matches_metadata(m, ms)))
 	};
 
+	let dir_excluder = |dir: &DirInfo| -> bool {
+		if dir.depth == 0 { return false; } // never exclude the root
+		if exclude_dir.iter().any(|re| re.is_match(&dir.name)) || exclude_dir_c.iter().any(|re| re.is_match(&dir.name)) { return true; }
+		if exclude_meta.is_empty() { return false; }
+		// A directory's size isn't known while walking, so size predicates only exclude files
+		dir.metadata.as_ref().is_some_and(|m| exclude_meta.iter().filter(|ms| ms.field != "size").any(|ms| matches_metadata(m, ms)))
+	};
 
 
+	let filtered = files_specified || dirs_specified || meta_specified || where_specified;
+	let ignores = if args.no_ignore { None } else { Some(IgnoreRules::root(&args.path)) };
 
-    // Build the root EntryInfo
-    let root_entry = build_directory_tree(
-        &args.path,
-        0,
-        &file_matcher,
-        &dir_matcher,
-        &args,
-		ParentRef::none()
-    )?;
+	let walker = Walker::new(args.threads);
+	let ctx = WalkContext {
+		file_matcher: &file_matcher,
+		dir_matcher: &dir_matcher,
+		prune_matcher: &prune_matcher,
+		file_excluder: &file_excluder,
+		dir_excluder: &dir_excluder,
+		args: &args,
+		walker: &walker,
+	};
 
+	if args.stream {
+		let totals = Tree::new(stream::stream_tree(root_metadata, &ctx, ignores)?);
+		if args.summary {
+			println!();
+			for line in summary_lines(&totals, filtered, None, &args) { println!("{}", line); }
+		}
+		return check_strict(totals.root(), &args);
+	}
 
+    // Build the tree from the root directory
+    let mut tree = Tree::new(DirInfo::new(&args.path, 0, None, false, Some(root_metadata)));
+    build_directory_tree(&mut tree, ROOT, &ctx, ignores)?;
 
-	fix_tree_recursive(&root_entry);
 
-    // Print the tree
-	let _ = print_tree_recursive(&root_entry, "", &file_matcher, &dir_matcher, &args);
 
+	let tree = sort_tree(fix_tree(tree), &args);
 
-    if args.summary {
-        let total_dirs = root_entry.borrow().total_dirs;
-		let total_files = root_entry.borrow().total_files;
-	    let total_size: u64 = root_entry.borrow().total_size;
+	// Hash everything only when %hash% is shown, --dupes alone just hashes the candidates
+	if get_format_string(&args, false).contains("%hash%") || (matches!(args.output, OutputMode::Csv | OutputMode::Tsv) && args.columns.iter().any(|c| c == "hash")) {
+		hash_all(&tree);
+	}
+	let dupes = if args.dupes { Some(find_dupes(&tree, &args)) } else { None };
+
+	match args.output {
+		OutputMode::Xml => output::tree_compat::print_xml(&tree, &args)?,
+		OutputMode::TreeJson => output::tree_compat::print_tree_json(&tree, &args)?,
+		OutputMode::Markdown => output::markdown::print_markdown(&tree, filtered, dupes.as_deref(), &args)?,
+		OutputMode::Html => output::html::print_html(&tree, filtered, dupes.as_deref(), &args)?,
+		OutputMode::Json => output::json::print_json(&tree, &args)?,
+		OutputMode::Ndjson => output::json::print_ndjson(&tree, &args)?,
+		OutputMode::Csv => output::csv::print_csv(&tree, &args, false)?,
+		OutputMode::Tsv => output::csv::print_csv(&tree, &args, true)?,
+		OutputMode::Tree => {
+			// Print the tree
+			if !args.top_only {
+				let _ = print_tree_recursive(&tree, ROOT, "", &args);
+			}
 
-		let p_total_dirs = root_entry.borrow().p_total_dirs;
-		let p_total_files = root_entry.borrow().p_total_files;
-	    let p_total_size: u64 = root_entry.borrow().p_total_size;
+			if let Some(n) = top {
+				if !args.top_only { println!(); }
+				for line in top_lines(&tree, n, args.top_kind, filtered, &args) { println!("{}", line); }
+			}
 
-		if files_specified || dirs_specified || meta_specified {
-	    	println!("\nMatched {} directories, {} files", p_total_dirs, p_total_files);
-        	println!("Matched total size: {} bytes", p_total_size);
-		} else { println!() }
+			if let Some(sets) = &dupes {
+				println!();
+				for line in dupes_lines(sets, &args) { println!("{}", line); }
+			}
 
-        println!("{} directories, {} files", total_dirs, total_files);
-        println!("Total size: {} bytes", total_size);
-    }
+			if args.summary {
+				println!();
+				for line in summary_lines(&tree, filtered, dupes.as_deref(), &args) { println!("{}", line); }
+			}
+		}
+	}
 
-    Ok(())
+	check_strict(tree.root(), &args)
 }
 
 
@@ -587,304 +1142,442 @@ fn main() -> Result<()> {
 
 
 
-fn build_directory_tree(
-    path: &Path,
-    current_depth: usize,
-    file_matcher: &dyn Fn(&FileInfo) -> (bool, Vec<bool>),
-    dir_matcher: &dyn Fn(&DirInfo) -> (bool, Vec<bool>),
-    args: &Args,
-    parent: ParentRef<DirInfo>,
-) -> Result<Rc<RefCell<DirInfo>>> {
-
-    let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
-	let full_path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
-    let dir =  Rc::new(RefCell::new(DirInfo {
-        path: path.to_path_buf(),
-        name,
-        depth: current_depth,
-        regex_matched: false,
-        parent: parent.clone(),
-
-		// total data
-        immediate_files_size: 0,
-        total_size: 0,
-		total_files: 0,
-		total_dirs: 0,
-
-		// parsed data,
-		p_immediate_files_size: 0,
-		p_total_size: 0,
-		p_total_files: 0,
-		p_total_dirs: 0,
-
-        sub_dirs: Vec::new(),
-		sub_files: Vec::new(),
-        contains_dir_matching_regex: false,
-        contains_file_matching_regex: false,
-		contains_meta_matching_regex: false,
-    }));
-
-	let drm = dir_matcher(&dir.borrow()).0;
-	dir.borrow_mut().regex_matched = drm;
+// The -S summary, shared by the outputs that show one. `dupes` are the --dupes sets when they were looked for
+fn summary_lines(tree: &Tree, filtered: bool, dupes: Option<&[DupeSet]>, args: &Args) -> Vec<String> {
+	let root = tree.root();
+	let mut lines = Vec::new();
 
+	if filtered {
+		lines.push(format!("Matched {} directories, {} files", root.p_total_dirs, root.p_total_files));
+		lines.push(format!("Matched total size: {}", fmt_size_summary(root.p_total_size(args.apparent_size), args)));
+	}
 
+	lines.push(format!("{} directories, {} files", root.total_dirs, root.total_files));
+	lines.push(format!("Total size: {}", fmt_size_summary(root.total_size(args.apparent_size), args)));
 
-    // Max depth reached
-    if current_depth >= args.depth { return Ok(dir); }
+	if args.prune_dirs {
+		lines.push(format!("Pruned {} directories (their contents were not traversed or counted)", root.pruned_dirs));
+	}
 
-    // Read directory
-    let entries = match fs::read_dir(path) {
-        Ok(e) => e,
-        Err(_) => return Ok(dir),
-    };
+	if root.total_errors > 0 {
+		lines.push(unreadable_summary(root.total_errors));
+	}
 
-	// Loop through the elements
-    for entry_result in entries {
-		let entry = entry_result?;
-		let path = entry.path();
-		let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
-
-		// Get the file size using windows. Using metadata  doesn't return the right file size in some cases for some reason
-		let mut high: u32 = 0;
-		let mut low: u32 = 0;
-		{
-			let pathos = path.clone().into_os_string();
-			let mut pathw: Vec<u16> = Vec::with_capacity(pathos.len() + 1);
-			pathw.extend(pathos.encode_wide());
-			pathw.push(0);
-			low = unsafe { GetCompressedFileSizeW(pathw.as_ptr(), &mut high) };
-			if low == INVALID_FILE_SIZE { low = 0; high = 0; }
-		}
+	if let Some(sets) = dupes {
+		lines.push(dupes_summary(sets, args));
+	}
 
-		// Handle file case
-    	if !path.is_dir() {
-			let file =  Rc::new(RefCell::new(FileInfo {
-				size: (u64::from(high) << 32 | u64::from(low)),
-				path: path,
-				name,
-				regex_matched: false,
-				parent: ParentRef::from_rc(&dir),
-			}));
-			let fm = file_matcher(&file.borrow()).0;
-			file.borrow_mut().regex_matched = fm;
-			dir.borrow_mut().sub_files.push(file);
-
-
-			// Update ancestors containing file matching
-			let fm = fm | dir.borrow().contains_file_matching_regex;
-			dir.borrow_mut().contains_file_matching_regex = fm;
-			let mut parent = dir.borrow_mut().parent.clone();
-			while parent.is_some() && parent.is_valid() {
-				let p = parent.upgrade().unwrap();
-				let mut p: std::cell::RefMut<'_, DirInfo> = p.borrow_mut();
-				p.contains_file_matching_regex = fm | p.contains_file_matching_regex;
-				parent = p.parent.clone();
-			}
-			continue;
-		} // -- file handling end
+	for by in &args.breakdown {
+		lines.push(String::new());
+		lines.extend(breakdown_lines(tree, *by, args));
+	}
 
+	lines
+}
 
 
 
+/// What every directory of a walk is matched and listed with, shared by build_directory_tree and --stream.
+#[derive(Clone, Copy)]
+struct WalkContext<'a> {
+	file_matcher: FileMatcher<'a>,
+	dir_matcher: DirMatcher<'a>,
+	prune_matcher: &'a dyn Fn(&DirInfo) -> bool,
+	file_excluder: &'a dyn Fn(&FileInfo) -> bool,
+	dir_excluder: &'a dyn Fn(&DirInfo) -> bool,
+	args: &'a Args,
+	walker: &'a Walker,
+}
 
-        // Recurse with current entry as parent
-        let subdir = build_directory_tree(
-            &path,
-            current_depth + 1,
-        	&file_matcher,
-    		&dir_matcher,
-            args,
-			ParentRef::from_rc(&dir)
-        )?;
+// Listed entries with whether each is ignored
+type Entries = Vec<(ListedEntry, bool)>;
+
+impl WalkContext<'_> {
+	/// Splits a directory's listing into its subdirectories and files. Ignored entries are skipped entirely unless they
+	/// should be shown dimmed, and an entry that couldn't even be named is reported on the directory through `error`.
+	fn split_listing(&self, listing: Vec<io::Result<ListedEntry>>, dir_ignored: bool, ignores: &Option<Rc<IgnoreRules>>, error: &mut Option<String>) -> (Entries, Entries) {
+		let mut entries = Vec::with_capacity(listing.len());
+		for entry in listing {
+			let entry = match entry {
+				Ok(entry) => entry,
+				Err(e) => { error.get_or_insert_with(|| error_message(&e)); continue; }
+			};
+			let entry_ignored = dir_ignored || ignores.as_ref().is_some_and(|i| i.is_ignored(&entry.name, entry.is_dir()));
+			if entry_ignored && !self.args.show_ignored { continue; }
+			entries.push((entry, entry_ignored));
+		}
+		entries.into_iter().partition(|(e, _)| e.is_dir())
+	}
 
+	/// The ignore rules for the entries of `dir`, built from its parent's rules and the names in its listing. The root's
+	/// own rules are loaded up front, so for it `ignores` are returned as they are.
+	fn dir_ignores(&self, dir: &DirInfo, ignores: Option<Rc<IgnoreRules>>, listing: &[io::Result<ListedEntry>]) -> Option<Rc<IgnoreRules>> {
+		if dir.depth == 0 { return ignores; }
+		let names: Vec<&str> = listing.iter().flatten().map(|e| e.name.as_str()).collect();
+		ignores.map(|i| i.child(&dir.name, &names))
+	}
 
-		// update ancestors containing dir matching
-		let dm = subdir.borrow().regex_matched | subdir.borrow().contains_dir_matching_regex | dir.borrow().contains_dir_matching_regex;
-		dir.borrow_mut().contains_dir_matching_regex = dm;
-		let mut parent = dir.borrow_mut().parent.clone();
-		while parent.is_some() && parent.is_valid() {
-			let p = parent.upgrade().unwrap();
-			let mut p = p.borrow_mut();
-			p.contains_dir_matching_regex = dm | p.contains_dir_matching_regex;
-			parent = p.parent.clone()
+	/// The FileInfo of a listed file, matched unless it's excluded. Excluded files are hidden along with their errors.
+	fn file_info(&self, entry: ListedEntry, ignored: bool, parent: DirId) -> FileInfo {
+		// The allocated size is used as metadata.len() doesn't return the on disk size for compressed/sparse files
+		let apparent_size = entry.apparent_size();
+		let (metadata, error) = split_metadata(entry.metadata);
+		let mut file = FileInfo {
+			size: entry.size,
+			apparent_size,
+			path: entry.path,
+			name: entry.name,
+			regex_matched: false,
+			ignored,
+			hash: Cell::new(None),
+			metadata,
+			error,
+			matches: Vec::new(),
+			parent,
+		};
+		if (self.file_excluder)(&file) {
+			file.error = None;
+		} else {
+			(file.regex_matched, file.matches) = (self.file_matcher)(&file);
 		}
+		file
+	}
+}
 
-		// Update immediate statistics
-		//let mut s = dir.borrow().immediate_files_size;
-		//for i in &dir.borrow().sub_files { s += i.borrow().size; }
-		//dir.borrow_mut().immediate_files_size = s;
+fn build_directory_tree(tree: &mut Tree, id: DirId, ctx: &WalkContext, ignores: Option<Rc<IgnoreRules>>) -> Result<()> {
+	let WalkContext { dir_matcher, prune_matcher, dir_excluder, args, walker, .. } = *ctx;
 
-        // Now update stats based on child
-		dir.borrow_mut().sub_dirs.push(subdir.clone());
+	// An excluded directory is hidden and not walked at all, so nothing below it is counted either
+	if dir_excluder(&tree.dirs[id]) {
+		tree.dirs[id].excluded = true;
+		walker.skip(&tree.dirs[id].path);
+		return Ok(());
+	}
 
+	let (drm, matches) = dir_matcher(&tree.dirs[id]);
+	tree.dirs[id].regex_matched = drm;
+	tree.dirs[id].matches = matches;
+
+	let current_depth = tree.dirs[id].depth;
+	let ignored = tree.dirs[id].ignored;
 
-    }
 
-	// We must order the sub-entries correctly as to get a nice output display that isn't too cluttered. We display files first then sub-directories.
-    dir.borrow_mut().sub_dirs.sort_by(|a, b| { a.borrow().path.cmp(&b.borrow().path) });
-	dir.borrow_mut().sub_files.sort_by(|a, b| { a.borrow().name.cmp(&b.borrow().name) });
 
+    // Max depth reached
+    if current_depth >= args.depth { return Ok(()); }
 
+	// Don't descend into directories that don't match (the root is always walked)
+	if args.prune_dirs && current_depth > 0 && !prune_matcher(&tree.dirs[id]) {
+		tree.dirs[id].pruned = true;
+		walker.skip(&tree.dirs[id].path);
+		return Ok(());
+	}
 
+    // Read directory. A directory that can't be read is shown with the reason, only the start directory is fatal
+    let listing = match walker.list(&tree.dirs[id].path) {
+        Ok(e) => e,
+        Err(e) if id == ROOT => anyhow::bail!("Can't read '{}': {}", args.path.display(), error_message(&e)),
+        Err(e) => {
+			tree.dirs[id].error = Some(error_message(&e));
+			return Ok(());
+		}
+    };
 
-    Ok(dir)
-}
+	let ignores = ctx.dir_ignores(&tree.dirs[id], ignores, &listing);
+	let (dir_entries, file_entries) = ctx.split_listing(listing, ignored, &ignores, &mut tree.dirs[id].error);
+
+	// Let the walker read the subdirectories that will be listed ahead of us
+	if current_depth + 1 < args.depth {
+		walker.prefetch(dir_entries.iter().map(|(e, _)| e.path.clone()).collect());
+	}
+
+	// Files first so they take a contiguous range of the arena
+	let first_file = tree.files.len();
+	let mut fm_any = false;
+    for (entry, entry_ignored) in file_entries {
+		let file = ctx.file_info(entry, entry_ignored, id);
+		fm_any |= file.regex_matched;
+		tree.files.push(file);
+	}
+	tree.dirs[id].sub_files = first_file..tree.files.len();
 
+	// Update ancestors containing file matching
+	if fm_any {
+		tree.dirs[id].contains_file_matching_regex = true;
+		for p in tree.ancestors(id).collect::<Vec<_>>() { tree.dirs[p].contains_file_matching_regex = true; }
+	}
 
 
 
-fn fix_tree_recursive(dir: &Rc<RefCell<DirInfo>>) {
+	// Then the subdirectories, also contiguous, each filled in by recursing with it as the parent
+	let first_dir = tree.dirs.len();
+	for (entry, entry_ignored) in &dir_entries {
+		tree.dirs.push(DirInfo::new(&entry.path, current_depth + 1, Some(id), *entry_ignored, entry.metadata.as_ref().ok().cloned()));
+	}
+	tree.dirs[id].sub_dirs = first_dir..tree.dirs.len();
+
+	for (i, (entry, _)) in dir_entries.iter().enumerate() {
+		let subdir = first_dir + i;
+        build_directory_tree(tree, subdir, ctx, ignores.clone())?;	// its own rules are added once it's listed
+
+		// update ancestors containing dir matching
+		let dm = tree.dirs[subdir].regex_matched | tree.dirs[subdir].contains_dir_matching_regex;
+		if dm {
+			tree.dirs[id].contains_dir_matching_regex = true;
+			for p in tree.ancestors(id).collect::<Vec<_>>() { tree.dirs[p].contains_dir_matching_regex = true; }
+		}
+    }
 
+    Ok(())
+}
 
-	let mut dir = dir.borrow_mut();
-	let mut ids: Vec<usize> = Vec::new();
 
-	// Loop through files
-	if dir.sub_files.len() > 0 { for i in 0..dir.sub_files.len() {
-		let file = dir.sub_files[i].borrow();
-		let rm = file.regex_matched;
-		let size = file.size;
-		drop(file);
 
-		// update total statistics
-		dir.immediate_files_size += size;
-		dir.total_size += size;
-		dir.total_files += 1;
 
-		// If file not matched then skip
-		if !rm { ids.push(i); continue; }
+// Totals every directory and drops the files and directories that didn't match and have nothing matching below them
+fn fix_tree(mut tree: Tree) -> Tree {
 
-		// update parsed statistics
-		dir.p_immediate_files_size += size;
-		dir.p_total_size += size;
-		dir.p_total_files += 1;
-	}}
+	// Backwards so every subdirectory is totalled before the directory that contains it
+	for id in (0..tree.dirs.len()).rev() {
+		if tree.dirs[id].error.is_some() { tree.dirs[id].total_errors += 1; }
 
-	// Remove unmatched files
-	for i in ids.iter().rev() { dir.sub_files.remove(*i); }
+		// Loop through files
+		for f in tree.dirs[id].sub_files.clone() {
+			let file = &tree.files[f];
+			let (rm, size, asize, failed) = (file.regex_matched, file.size, file.apparent_size, file.error.is_some());
+			let dir = &mut tree.dirs[id];
+			dir.total_errors += failed as u64;
 
+			// update total statistics
+			dir.immediate_files_size += size;
+			dir.total_size += size;
+			dir.immediate_files_apparent_size += asize;
+			dir.total_apparent_size += asize;
+			dir.total_files += 1;
 
+			// If file not matched then skip
+			if !rm { continue; }
 
-	// Recurse over matched subdirs
-	ids.clear();
-	if dir.sub_dirs.len() > 0 { for i in 0..dir.sub_dirs.len() {
-		let subdir = dir.sub_dirs[i].borrow_mut();
-		let rm = subdir.regex_matched || subdir.contains_file_matching_regex || subdir.contains_dir_matching_regex;
-		drop(subdir);
+			// update parsed statistics
+			dir.p_immediate_files_size += size;
+			dir.p_total_size += size;
+			dir.p_immediate_files_apparent_size += asize;
+			dir.p_total_apparent_size += asize;
+			dir.p_total_files += 1;
+		}
 
-		// recurse matched directories
-		fix_tree_recursive(&dir.sub_dirs[i].clone());
+		// Add this directory into its parent
+		let Some(parent) = tree.dirs[id].parent else { continue };
+		let (subdir, dir) = { let (a, b) = tree.dirs.split_at_mut(id); (&b[0], &mut a[parent]) };
 
 		// update total statistics
-		updm!(dir.total_size, += dir.sub_dirs[i].borrow().total_size);
-		updm!(dir.total_files, += dir.sub_dirs[i].borrow().total_files);
-		updm!(dir.total_dirs, += 1 + dir.sub_dirs[i].borrow().total_dirs);
+		updm!(dir.total_size, += subdir.total_size);
+		updm!(dir.total_apparent_size, += subdir.total_apparent_size);
+		updm!(dir.total_files, += subdir.total_files);
+		updm!(dir.total_dirs, += 1 + subdir.total_dirs);
+		updm!(dir.pruned_dirs, += subdir.pruned_dirs + subdir.pruned as u64);
+		updm!(dir.total_errors, += subdir.total_errors);
 
 		// Skip directory if not matched
-		if !rm { ids.push(i); continue; }
+		if !is_kept(subdir) { continue; }
 
+		// update parsed statistics
+		updm!(dir.p_total_size, += subdir.p_total_size);
+		updm!(dir.p_total_apparent_size, += subdir.p_total_apparent_size);
+		updm!(dir.p_total_files, += subdir.p_total_files);
+		updm!(dir.p_total_dirs, += 1 + subdir.p_total_dirs);
+	}
 
+	// remove unmatched files and dirs, what couldn't be read stays to show why
+	tree.rebuild(|tree, id| {
+		let dir = &tree.dirs[id];
+		let files = dir.sub_files.clone().filter(|&f| tree.files[f].regex_matched || tree.files[f].error.is_some());
+		let dirs = dir.sub_dirs.clone().filter(|&d| is_kept(&tree.dirs[d]) || tree.dirs[d].total_errors > 0);
+		(files.collect(), dirs.collect())
+	})
+}
 
-		// update parsed statistics
-		updm!(dir.p_total_size, += dir.sub_dirs[i].borrow().p_total_size);
-		updm!(dir.p_total_files, += dir.sub_dirs[i].borrow().p_total_files);
-		updm!(dir.p_total_dirs, += 1 + dir.sub_dirs[i].borrow().p_total_dirs);
+// Whether a directory stays in the tree after fix_tree
+fn is_kept(dir: &DirInfo) -> bool {
+	dir.regex_matched || dir.contains_file_matching_regex || dir.contains_dir_matching_regex
+}
+
+
+
+// The --sort key of a file, None sorts by name only
+fn file_sort_key(file: &FileInfo, args: &Args) -> Option<SortKey> {
+	let time = |f: fn(&fs::Metadata) -> std::io::Result<SystemTime>| SortKey::Time(file.metadata.as_ref().and_then(|m| f(m).ok()));
+	match args.sort {
+		SortBy::Name => None,
+		SortBy::Size | SortBy::TotalSize | SortBy::PTotalSize => Some(SortKey::Num(file.size(args.apparent_size))),
+		SortBy::Mtime => Some(time(fs::Metadata::modified)),
+		SortBy::Ctime => Some(time(fs::Metadata::created)),
+		SortBy::Atime => Some(time(fs::Metadata::accessed)),
+		SortBy::Ext => Some(SortKey::Text(file.path.extension().map(|e| e.to_string_lossy().to_string()).unwrap_or_default())),
+		SortBy::Count => Some(SortKey::Num(0)),
+	}
+}
+
+// The --sort key of a directory, sizes are the directory totals
+fn dir_sort_key(dir: &DirInfo, args: &Args) -> Option<SortKey> {
+	let time = |f: fn(&fs::Metadata) -> std::io::Result<SystemTime>| SortKey::Time(dir.metadata.as_ref().and_then(|m| f(m).ok()));
+	match args.sort {
+		SortBy::Name => None,
+		SortBy::Size | SortBy::TotalSize => Some(SortKey::Num(dir.total_size(args.apparent_size))),
+		SortBy::PTotalSize => Some(SortKey::Num(dir.p_total_size(args.apparent_size))),
+		SortBy::Mtime => Some(time(fs::Metadata::modified)),
+		SortBy::Ctime => Some(time(fs::Metadata::created)),
+		SortBy::Atime => Some(time(fs::Metadata::accessed)),
+		SortBy::Ext => Some(SortKey::Text(dir.path.extension().map(|e| e.to_string_lossy().to_string()).unwrap_or_default())),
+		SortBy::Count => Some(SortKey::Num(dir.total_files + dir.total_dirs)),
+	}
+}
 
-	}}
+// Compare by key then name. Numbers and times sort largest/newest first, text alphabetically, --reverse flips both
+fn compare_entries(a: (&Option<SortKey>, &Path), b: (&Option<SortKey>, &Path), args: &Args) -> std::cmp::Ordering {
+	let by_key = match (a.0, b.0) {
+		(Some(SortKey::Text(x)), Some(SortKey::Text(y))) => compare_names(x, y, args.natural, args.ignore_case),
+		(Some(x), Some(y)) => y.partial_cmp(x).unwrap_or(std::cmp::Ordering::Equal),
+		_ => std::cmp::Ordering::Equal,
+	};
+	let name = |p: &Path| p.file_name().unwrap_or_default().to_string_lossy().to_string();
+	let ord = by_key.then_with(|| compare_names(&name(a.1), &name(b.1), args.natural, args.ignore_case)).then_with(|| a.1.cmp(b.1));
+	if args.reverse { ord.reverse() } else { ord }
+}
 
+// Sorts ids by key then name then path, see compare_entries
+fn sort_ids(ids: &mut Vec<usize>, key: impl Fn(usize) -> Option<SortKey>, path: impl Fn(usize) -> PathBuf, args: &Args) {
+	let mut keyed: Vec<(Option<SortKey>, PathBuf, usize)> = ids.drain(..).map(|id| (key(id), path(id), id)).collect();
 
-	// remove unmatched dirs
-	for i in ids.iter().rev() { dir.sub_dirs.remove(*i); }
+	keyed.sort_by(|a, b| compare_entries((&a.0, &a.1), (&b.0, &b.1), args));
 
+	ids.extend(keyed.into_iter().map(|(_, _, id)| id));
+}
 
+// A child of a directory in display order, see ordered_entries
+#[derive(Debug, Clone, Copy)]
+enum TreeEntry {
+	File(FileId),
+	Dir(DirId),
+}
+
+// The children of a directory in the --order display order. Both ranges are already sorted, mixed merges them by the sort key
+fn ordered_entries(tree: &Tree, dir: DirId, args: &Args) -> Vec<TreeEntry> {
+	let dir = &tree.dirs[dir];
+	let files = dir.sub_files.clone().map(TreeEntry::File);
+	let dirs = dir.sub_dirs.clone().map(TreeEntry::Dir);
+	match args.order {
+		Order::FilesFirst => files.chain(dirs).collect(),
+		Order::DirsFirst => dirs.chain(files).collect(),
+		Order::Mixed => {
+			let key = |e: TreeEntry| match e {
+				TreeEntry::File(f) => { let f = &tree.files[f]; (file_sort_key(f, args), f.path.clone(), e) }
+				TreeEntry::Dir(d) => { let d = &tree.dirs[d]; (dir_sort_key(d, args), d.path.clone(), e) }
+			};
+			merge_sorted(files.map(key).collect(), dirs.map(key).collect(), args)
+		}
+	}
+}
 
+// Merges a directory's sorted files and sorted directories, given as (key, path, item), into one sorted list. Files go
+// first on ties
+fn merge_sorted<T>(files: Vec<(Option<SortKey>, PathBuf, T)>, dirs: Vec<(Option<SortKey>, PathBuf, T)>, args: &Args) -> Vec<T> {
+	let mut merged = Vec::with_capacity(files.len() + dirs.len());
+	let mut files = files.into_iter().peekable();
+	let mut dirs = dirs.into_iter().peekable();
+	loop {
+		let take_file = match (files.peek(), dirs.peek()) {
+			(Some(f), Some(d)) => compare_entries((&f.0, &f.1), (&d.0, &d.1), args) != std::cmp::Ordering::Greater,
+			(Some(_), None) => true,
+			(None, Some(_)) => false,
+			(None, None) => break,
+		};
+		merged.push(if take_file { files.next().unwrap().2 } else { dirs.next().unwrap().2 });
+	}
+	merged
+}
 
+// We must order the sub-entries correctly as to get a nice output display that isn't too cluttered. Runs after
+// fix_tree so the size keys can use the totals
+fn sort_tree(tree: Tree, args: &Args) -> Tree {
+	tree.rebuild(|tree, id| {
+		let dir = &tree.dirs[id];
+		let mut files: Vec<FileId> = dir.sub_files.clone().collect();
+		let mut dirs: Vec<DirId> = dir.sub_dirs.clone().collect();
+		sort_ids(&mut files, |f| file_sort_key(&tree.files[f], args), |f| tree.files[f].path.clone(), args);
+		sort_ids(&mut dirs, |d| dir_sort_key(&tree.dirs[d], args), |d| tree.dirs[d].path.clone(), args);
+		(files, dirs)
+	})
 }
 
 
 
 fn print_tree_recursive(
-    dir: &Rc<RefCell<DirInfo>>,
+    tree: &Tree,
+    dir: DirId,
     prefix: &str,
-    file_matcher: &dyn Fn(&FileInfo) -> (bool, Vec<bool>),
-    dir_matcher: &dyn Fn(&DirInfo) -> (bool, Vec<bool>),
     args: &Args,
 ) -> Result<()> {
-    let dir_ref = dir.borrow();
-
-	let fformat_str = get_format_string(&args, false);
-	let dformat_str = get_format_string(&args, true);
-
-    // Print files
-    if dir_ref.sub_files.len() > 0 {
-        for i in 0..dir_ref.sub_files.len() {
-
-            let file = dir_ref.sub_files[i].borrow();
-            let metadata = fs::metadata(&file.path).unwrap_or_else(|_| fs::metadata(&args.path).unwrap()); // fallback
-            let s = if args.no_indent {
-                "".to_string()
-            } else {
-                if i == dir_ref.sub_files.len() - 1 && dir_ref.sub_dirs.len() == 0 {
-                    "└── ".to_string()
-                } else {
-                    "├── ".to_string()
-                }
-            };
-
-            // Format the additional info using the format string
-            let format_values = get_file_format_values(&*file, &metadata);
-            let formatted_info = format_string(&fformat_str, &format_values, false);
-
-            println!("{}{}{} {}",
-                prefix,
-                s,
-                get_combined_color(&file.name, &file_matcher(&*file).1, false),
-                formatted_info.dimmed()
-            );
-        }
-    }
-
-    // Print directories
-    if dir_ref.sub_dirs.len() > 0 {
-        for i in 0..dir_ref.sub_dirs.len() {
-            let subdir = dir_ref.sub_dirs[i].borrow();
-            let metadata = fs::metadata(&subdir.path).unwrap_or_else(|_| fs::metadata(&args.path).unwrap()); // fallback
-            let s = if args.no_indent {
-                "".to_string()
-            } else {
-                if i == dir_ref.sub_dirs.len() - 1 {
-                    "└── ".to_string()
-                } else {
-                    "├── ".to_string()
-                }
-            };
-
-            // Format the additional info using the format string
-            let format_values = get_dir_format_values(&*subdir, &metadata);
-            let formatted_info = format_string(&dformat_str, &format_values, true);
-
-            println!("{}{}{} {}",
-                prefix,
-                s,
-                get_combined_color(&subdir.name, &dir_matcher(&*subdir).1, true),
-                formatted_info.dimmed()
-            );
-
-            let child_prefix = if args.no_indent {
-                "".to_string()
-            } else {
-                prefix.to_owned() + if i == dir_ref.sub_dirs.len() - 1 {
-                    "    "
-                } else {
-                    "│   "
-                }
-            };
+	let fformat_str = get_format_string(args, false);
+	let dformat_str = get_format_string(args, true);
+
+	let entries = ordered_entries(tree, dir, args);
+	for (i, entry) in entries.iter().enumerate() {
+		let last = i == entries.len() - 1;
+		let s = if args.no_indent {
+			"".to_string()
+		} else {
+			if last {
+				"└── ".to_string()
+			} else {
+				"├── ".to_string()
+			}
+		};
+
+		match *entry {
+			// Print files
+			TreeEntry::File(file) => {
+				let file = &tree.files[file];
+				// Format the additional info using the format string
+				let format_values = get_file_format_values(file, args);
+				let formatted_info = format_string(&fformat_str, &format_values, false);
+
+				println!("{}{}{} {}",
+					prefix,
+					s,
+					dim_ignored(get_combined_color(&file.name, &file.matches, false), file.ignored),
+					info_or_error(formatted_info, &file.error)
+				);
+			}
 
-            print_tree_recursive(&dir_ref.sub_dirs[i], &child_prefix, file_matcher, dir_matcher, args)?;
-        }
-    }
+			// Print directories
+			TreeEntry::Dir(subdir_id) => {
+				let subdir = &tree.dirs[subdir_id];
+				// Format the additional info using the format string
+				let format_values = get_dir_format_values(subdir, args);
+				let formatted_info = format_string(&dformat_str, &format_values, true);
+
+				println!("{}{}{} {}",
+					prefix,
+					s,
+					dim_ignored(get_combined_color(&subdir.name, &subdir.matches, true), subdir.ignored),
+					info_or_error(formatted_info, &subdir.error)
+				);
+
+				let child_prefix = if args.no_indent {
+					"".to_string()
+				} else {
+					prefix.to_owned() + if last {
+						"    "
+					} else {
+						"│   "
+					}
+				};
+
+				print_tree_recursive(tree, subdir_id, &child_prefix, args)?;
+			}
+		}
+	}
 
     Ok(())
 }
-
-
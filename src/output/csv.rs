@@ -0,0 +1,120 @@
+use std::io::Write;
+use std::collections::HashMap;
+use anyhow::Result;
+
+use crate::{Args, FORMAT_TOKENS, TreeEntry, get_file_format_values, get_dir_format_values, ordered_entries};
+use crate::tree::{Tree, DirId, ROOT};
+
+
+
+// RFC 4180: fields with a separator, quote or line break are quoted and quotes doubled
+fn csv_field(value: &str) -> String {
+	if value.contains([',', '"', '\n', '\r']) {
+		format!("\"{}\"", value.replace('"', "\"\""))
+	} else {
+		value.to_string()
+	}
+}
+
+// TSV has no quoting, so tabs, line breaks and backslashes are escaped like most TSV readers expect
+fn tsv_field(value: &str) -> String {
+	value.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n").replace('\r', "\\r")
+}
+
+/// Checks the --columns tokens, before anything is walked.
+pub fn check_columns(args: &Args) -> Result<()> {
+	// Every token is a valid column for both, files just leave the directory only ones empty
+	for column in &args.columns {
+		if !FORMAT_TOKENS.contains(&column.as_str()) {
+			anyhow::bail!("Unknown --columns token '{}' (expected one of {})", column, FORMAT_TOKENS.join(", "));
+		}
+	}
+	Ok(())
+}
+
+/// Prints one row per entry below the start directory with the --columns tokens, after a header row.
+pub fn print_csv(tree: &Tree, args: &Args, tsv: bool) -> Result<()> {
+	let (sep, field): (&str, fn(&str) -> String) = if tsv { ("\t", tsv_field) } else { (",", csv_field) };
+	let mut out = std::io::stdout().lock();
+	writeln!(out, "{}", args.columns.iter().map(|c| field(c)).collect::<Vec<_>>().join(sep))?;
+	write_rows(&mut out, tree, ROOT, args, sep, field)
+}
+
+fn write_row(out: &mut impl Write, values: &HashMap<&str, String>, args: &Args, sep: &str, field: fn(&str) -> String) -> Result<()> {
+	let row: Vec<String> = args.columns.iter().map(|c| field(values.get(c.as_str()).map_or("", |v| v.as_str()))).collect();
+	writeln!(out, "{}", row.join(sep))?;
+	Ok(())
+}
+
+fn write_rows(out: &mut impl Write, tree: &Tree, dir: DirId, args: &Args, sep: &str, field: fn(&str) -> String) -> Result<()> {
+	for entry in ordered_entries(tree, dir, args) {
+		match entry {
+			TreeEntry::File(file) => {
+				let file = &tree.files[file];
+				write_row(out, &get_file_format_values(file, args), args, sep, field)?;
+			}
+			TreeEntry::Dir(subdir_id) => {
+				let subdir = &tree.dirs[subdir_id];
+				write_row(out, &get_dir_format_values(subdir, args), args, sep, field)?;
+				write_rows(out, tree, subdir_id, args, sep, field)?;
+			}
+		}
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use clap::Parser;
+	use crate::DirInfo;
+
+	fn columns(columns: &str) -> Args {
+		Args::parse_from(["t", "-o", "csv", "--columns", columns])
+	}
+
+	#[test]
+	fn every_advertised_token_is_a_column() {
+		assert!(check_columns(&columns(&FORMAT_TOKENS.join(","))).is_ok());
+		assert!(check_columns(&columns("path,readonly,modified,hash")).is_ok());
+		let help = crate::token_help();
+		for token in FORMAT_TOKENS { assert!(help.contains(token), "{}", token); }
+	}
+
+	#[test]
+	fn unknown_columns_list_every_token() {
+		let e = check_columns(&columns("path,colour")).unwrap_err().to_string();
+		assert!(e.starts_with("Unknown --columns token 'colour'"), "{}", e);
+		for token in FORMAT_TOKENS { assert!(e.contains(token), "{}", token); }
+	}
+
+	#[test]
+	fn format_values_only_use_advertised_tokens() {
+		let args = columns("path");
+		let dir = DirInfo::new(&std::env::temp_dir(), 0, None, false, std::fs::metadata(std::env::temp_dir()).ok());
+		for token in get_dir_format_values(&dir, &args).keys() { assert!(FORMAT_TOKENS.contains(token), "{}", token); }
+	}
+
+	#[test]
+	fn csv_plain_fields_are_unquoted() {
+		assert_eq!(csv_field("src/main.rs"), "src/main.rs");
+		assert_eq!(csv_field(""), "");
+		assert_eq!(csv_field("tab\there"), "tab\there");
+	}
+
+	#[test]
+	fn csv_quotes_separators_quotes_and_line_breaks() {
+		assert_eq!(csv_field("a,b"), "\"a,b\"");
+		assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+		assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+		assert_eq!(csv_field("cr\r"), "\"cr\r\"");
+	}
+
+	#[test]
+	fn tsv_escapes_tabs_line_breaks_and_backslashes() {
+		assert_eq!(tsv_field("a,b \"c\""), "a,b \"c\"");
+		assert_eq!(tsv_field("a\tb"), "a\\tb");
+		assert_eq!(tsv_field("a\nb\rc"), "a\\nb\\rc");
+		assert_eq!(tsv_field("C:\\dir"), "C:\\\\dir");
+	}
+}
@@ -0,0 +1,99 @@
+use std::io::Write;
+use anyhow::Result;
+
+use crate::{Args, TreeEntry, DIR_CSS_COLORS, FILE_CSS_COLORS, color_index, fmt_size, format_string, get_format_string,
+	get_file_format_values, get_dir_format_values, ordered_entries, summary_lines};
+use crate::dupes::DupeSet;
+use crate::tree::{Tree, DirId, ROOT};
+
+
+const STYLE: &str = r#"
+body { font-family: ui-monospace, Consolas, monospace; font-size: 14px; margin: 2em; color: #222; }
+h1 { font-size: 1.2em; }
+.summary { background: #f4f4f4; padding: .8em 1em; border-radius: 4px; }
+ul { list-style: none; padding-left: 1.4em; margin: 0; border-left: 1px dotted #bbb; }
+li { margin: 2px 0; white-space: nowrap; }
+summary { cursor: pointer; }
+.dir { font-weight: bold; }
+.nomatch.dir { color: #3b78ff; }
+.nomatch.file { color: #c50f1f; }
+.ignored { opacity: .5; }
+.info { color: #888; }
+.error { color: #c50f1f; }
+.size { display: inline-block; min-width: 6em; text-align: right; color: #555; }
+.bar { display: inline-block; width: 100px; height: .7em; background: #eee; vertical-align: middle; margin: 0 .4em; }
+.bar span { display: block; height: 100%; background: #7aa6da; }
+.bar.ratio span { background: #b9ca4a; }
+"#;
+
+fn escape(s: &str) -> String {
+	s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&#39;")
+}
+
+// A bar filled to `part / whole`, with the percentage as its tooltip
+fn bar(part: u64, whole: u64, class: &str, what: &str) -> String {
+	let pct = if whole == 0 { 0.0 } else { part as f64 * 100.0 / whole as f64 };
+	format!("<span class=\"bar {}\" title=\"{:.1}% {}\"><span style=\"width:{:.1}%\"></span></span>", class, pct, what, pct)
+}
+
+// The format info, or why the entry couldn't be read
+fn info_span(info: &str, error: &Option<String>) -> String {
+	match error {
+		Some(e) => format!("<span class=\"error\">[{}]</span>", escape(e)),
+		None => format!("<span class=\"info\">{}</span>", escape(info)),
+	}
+}
+
+// The name colored like the terminal output would color it
+fn name_span(name: &str, matches: &[bool], is_dir: bool, ignored: bool) -> String {
+	let kind = if is_dir { "dir" } else { "file" };
+	let ignored = if ignored { " ignored" } else { "" };
+	match color_index(matches, is_dir) {
+		Some(i) => format!("<span class=\"{}{}\" style=\"color:{}\">{}</span>", kind, ignored, if is_dir { DIR_CSS_COLORS[i] } else { FILE_CSS_COLORS[i] }, escape(name)),
+		None => format!("<span class=\"nomatch {}{}\">{}</span>", kind, ignored, escape(name)),
+	}
+}
+
+/// Prints a single offline html page: the -S summary, then the tree with collapsible directories.
+///
+/// Every entry has a bar for its share of the root's total size, directories also get one for their matched share (p_total_size / total_size).
+pub fn print_html(tree: &Tree, filtered: bool, dupes: Option<&[DupeSet]>, args: &Args) -> Result<()> {
+	let mut out = std::io::stdout().lock();
+	let title = escape(&args.path.to_string_lossy());
+	let root_total = tree.root().total_size(args.apparent_size);
+
+	writeln!(out, "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>{}</style>\n</head>\n<body>", title, STYLE)?;
+	writeln!(out, "<h1>{}</h1>", title)?;
+	writeln!(out, "<pre class=\"summary\">{}</pre>", escape(&summary_lines(tree, filtered, dupes, args).join("\n")))?;
+	writeln!(out, "<ul>")?;
+	write_dir(&mut out, tree, ROOT, root_total, args)?;
+	writeln!(out, "</ul>\n</body>\n</html>")?;
+	Ok(())
+}
+
+fn write_dir(out: &mut impl Write, tree: &Tree, dir: DirId, root_total: u64, args: &Args) -> Result<()> {
+	let fformat_str = get_format_string(args, false);
+	let dformat_str = get_format_string(args, true);
+	for entry in ordered_entries(tree, dir, args) {
+		match entry {
+			TreeEntry::File(file) => {
+				let file = &tree.files[file];
+				let info = format_string(&fformat_str, &get_file_format_values(file, args), false);
+				let size = file.size(args.apparent_size);
+				writeln!(out, "<li><span class=\"size\">{}</span>{}{} {}</li>",
+					fmt_size(size, args), bar(size, root_total, "share", "of the total size"), name_span(&file.name, &file.matches, false, file.ignored), info_span(&info, &file.error))?;
+			}
+			TreeEntry::Dir(subdir_id) => {
+				let subdir = &tree.dirs[subdir_id];
+				let info = format_string(&dformat_str, &get_dir_format_values(subdir, args), true);
+				let total = subdir.total_size(args.apparent_size);
+				writeln!(out, "<li><details open><summary><span class=\"size\">{}</span>{}{}{} {}</summary>\n<ul>",
+					fmt_size(total, args), bar(total, root_total, "share", "of the total size"), bar(subdir.p_total_size(args.apparent_size), total, "ratio", "matched"),
+					name_span(&subdir.name, &subdir.matches, true, subdir.ignored), info_span(&info, &subdir.error))?;
+				write_dir(out, tree, subdir_id, root_total, args)?;
+				writeln!(out, "</ul></details></li>")?;
+			}
+		}
+	}
+	Ok(())
+}
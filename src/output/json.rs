@@ -0,0 +1,100 @@
+use std::io::Write;
+use anyhow::Result;
+use serde_json::{json, Map, Value};
+
+use crate::{Args, FileInfo, DirInfo, TreeEntry, ordered_entries};
+use crate::tree::{Tree, DirId, ROOT};
+use super::timestamps;
+
+
+
+// The fields shared by the nested and the streaming output
+fn file_object(file: &FileInfo) -> Map<String, Value> {
+	let metadata = file.metadata.as_ref();
+	let mut obj = Map::new();
+	obj.insert("type".into(), json!("file"));
+	obj.insert("name".into(), json!(file.name));
+	obj.insert("path".into(), json!(file.path.to_string_lossy()));
+	obj.insert("size".into(), json!(file.size));
+	obj.insert("apparent_size".into(), json!(file.apparent_size));
+	for (k, v) in timestamps(metadata) { obj.insert(k.into(), json!(v)); }
+	obj.insert("readonly".into(), json!(metadata.map(|m| m.permissions().readonly())));
+	obj.insert("ignored".into(), json!(file.ignored));
+	obj.insert("error".into(), json!(file.error));
+	obj.insert("matched".into(), json!(file.regex_matched));
+	obj.insert("matches".into(), json!(file.matches));
+	obj
+}
+
+fn dir_object(dir: &DirInfo) -> Map<String, Value> {
+	let metadata = dir.metadata.as_ref();
+	let mut obj = Map::new();
+	obj.insert("type".into(), json!("directory"));
+	obj.insert("name".into(), json!(dir.name));
+	obj.insert("path".into(), json!(dir.path.to_string_lossy()));
+	obj.insert("depth".into(), json!(dir.depth));
+	obj.insert("immediate_files_size".into(), json!(dir.immediate_files_size));
+	obj.insert("total_size".into(), json!(dir.total_size));
+	obj.insert("total_files".into(), json!(dir.total_files));
+	obj.insert("total_dirs".into(), json!(dir.total_dirs));
+	obj.insert("immediate_files_apparent_size".into(), json!(dir.immediate_files_apparent_size));
+	obj.insert("total_apparent_size".into(), json!(dir.total_apparent_size));
+	obj.insert("p_immediate_files_size".into(), json!(dir.p_immediate_files_size));
+	obj.insert("p_total_size".into(), json!(dir.p_total_size));
+	obj.insert("p_total_files".into(), json!(dir.p_total_files));
+	obj.insert("p_total_dirs".into(), json!(dir.p_total_dirs));
+	obj.insert("p_immediate_files_apparent_size".into(), json!(dir.p_immediate_files_apparent_size));
+	obj.insert("p_total_apparent_size".into(), json!(dir.p_total_apparent_size));
+	obj.insert("pruned".into(), json!(dir.pruned));
+	obj.insert("pruned_dirs".into(), json!(dir.pruned_dirs));
+	obj.insert("total_errors".into(), json!(dir.total_errors));
+	for (k, v) in timestamps(metadata) { obj.insert(k.into(), json!(v)); }
+	obj.insert("readonly".into(), json!(metadata.map(|m| m.permissions().readonly())));
+	obj.insert("ignored".into(), json!(dir.ignored));
+	obj.insert("error".into(), json!(dir.error));
+	obj.insert("matched".into(), json!(dir.regex_matched));
+	obj.insert("matches".into(), json!(dir.matches));
+	obj
+}
+
+fn dir_tree(tree: &Tree, dir: DirId) -> Value {
+	let dir = &tree.dirs[dir];
+	let mut obj = dir_object(dir);
+	let files: Vec<Value> = dir.sub_files.clone().map(|f| Value::Object(file_object(&tree.files[f]))).collect();
+	let dirs: Vec<Value> = dir.sub_dirs.clone().map(|d| dir_tree(tree, d)).collect();
+	obj.insert("files".into(), Value::Array(files));
+	obj.insert("directories".into(), Value::Array(dirs));
+	Value::Object(obj)
+}
+
+/// Prints the filtered tree as one JSON document rooted at the start directory.
+pub fn print_json(tree: &Tree, args: &Args) -> Result<()> {
+	let mut out = std::io::stdout().lock();
+	serde_json::to_writer_pretty(&mut out, &dir_tree(tree, ROOT))?;
+	writeln!(out)?;
+	Ok(())
+}
+
+/// Prints one JSON object per line for every entry below the start directory, in the same order as the tree. Directories
+/// that are only there for a match below them, and entries only kept for their error, have `"matched": false`.
+pub fn print_ndjson(tree: &Tree, args: &Args) -> Result<()> {
+	let mut out = std::io::stdout().lock();
+	write_ndjson(&mut out, tree, ROOT, args)
+}
+
+fn write_ndjson(out: &mut impl Write, tree: &Tree, dir: DirId, args: &Args) -> Result<()> {
+	for entry in ordered_entries(tree, dir, args) {
+		match entry {
+			TreeEntry::File(file) => {
+				serde_json::to_writer(&mut *out, &file_object(&tree.files[file]))?;
+				writeln!(out)?;
+			}
+			TreeEntry::Dir(subdir) => {
+				serde_json::to_writer(&mut *out, &dir_object(&tree.dirs[subdir]))?;
+				writeln!(out)?;
+				write_ndjson(out, tree, subdir, args)?;
+			}
+		}
+	}
+	Ok(())
+}
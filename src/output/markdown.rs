@@ -0,0 +1,89 @@
+use std::io::Write;
+use anyhow::Result;
+
+use crate::{Args, MarkdownStyle, TreeEntry, format_string, get_format_string, get_file_format_values, get_dir_format_values,
+	ordered_entries, print_tree_recursive, summary_lines};
+use crate::dupes::DupeSet;
+use crate::tree::{Tree, DirId, ROOT};
+
+
+
+// Backslash escape the characters markdown would otherwise interpret in names and format info
+fn escape(s: &str) -> String {
+	let mut out = String::with_capacity(s.len());
+	for c in s.chars() {
+		if "\\`*_[]<>|~".contains(c) { out.push('\\'); }
+		out.push(c);
+	}
+	out
+}
+
+// Percent encode what would end or break a markdown link target
+fn link_target(path: &str) -> String {
+	path.replace('\\', "/").replace('%', "%25").replace(' ', "%20").replace('(', "%28").replace(')', "%29").replace('<', "%3C").replace('>', "%3E")
+}
+
+/// Prints the filtered tree as markdown: the usual tree inside a fenced block, or a nested bullet list whose names can link
+/// to the entries relative to the root.
+pub fn print_markdown(tree: &Tree, filtered: bool, dupes: Option<&[DupeSet]>, args: &Args) -> Result<()> {
+	// Markdown never wants ANSI escapes
+	colored::control::set_override(false);
+
+	match args.md_style {
+		MarkdownStyle::Fence => {
+			println!("```text");
+			println!("{}", args.path.display());
+			print_tree_recursive(tree, ROOT, "", args)?;
+			if args.summary {
+				println!();
+				for line in summary_lines(tree, filtered, dupes, args) { println!("{}", line); }
+			}
+			println!("```");
+		}
+		MarkdownStyle::List => {
+			let mut out = std::io::stdout().lock();
+			write_list(&mut out, tree, ROOT, "", args)?;
+			if args.summary {
+				writeln!(out)?;
+				for line in summary_lines(tree, filtered, dupes, args) { writeln!(out, "{}  ", escape(&line))?; }
+			}
+		}
+	}
+	Ok(())
+}
+
+fn entry(path: &std::path::Path, name: &str, is_dir: bool, args: &Args) -> String {
+	let label = if is_dir { format!("**{}/**", escape(name)) } else { escape(name) };
+	if !args.md_links { return label; }
+	let rel = path.strip_prefix(&args.path).unwrap_or(path).to_string_lossy().to_string();
+	format!("[{}]({}{})", label, link_target(&rel), if is_dir { "/" } else { "" })
+}
+
+// The format info, or why the entry couldn't be read
+fn info_or_error(info: &str, error: &Option<String>) -> String {
+	match error {
+		Some(e) => format!("**\\[{}\\]**", escape(e)),
+		None => escape(info),
+	}
+}
+
+fn write_list(out: &mut impl Write, tree: &Tree, dir: DirId, indent: &str, args: &Args) -> Result<()> {
+	let fformat_str = get_format_string(args, false);
+	let dformat_str = get_format_string(args, true);
+	for child in ordered_entries(tree, dir, args) {
+		match child {
+			TreeEntry::File(file) => {
+				let file = &tree.files[file];
+				let info = format_string(&fformat_str, &get_file_format_values(file, args), false);
+				writeln!(out, "{}- {} {}", indent, entry(&file.path, &file.name, false, args), info_or_error(&info, &file.error))?;
+			}
+			TreeEntry::Dir(subdir_id) => {
+				let subdir = &tree.dirs[subdir_id];
+				let info = format_string(&dformat_str, &get_dir_format_values(subdir, args), true);
+				writeln!(out, "{}- {} {}", indent, entry(&subdir.path, &subdir.name, true, args), info_or_error(&info, &subdir.error))?;
+				write_list(out, tree, subdir_id, &format!("{}  ", indent), args)?;
+			}
+		}
+	}
+	Ok(())
+}
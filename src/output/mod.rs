@@ -0,0 +1,28 @@
+use std::fs;
+use std::time::SystemTime;
+use chrono::{DateTime, Utc, SecondsFormat};
+
+use crate::{FileInfo, DirInfo};
+
+pub mod json;
+pub mod csv;
+pub mod html;
+pub mod markdown;
+pub mod tree_compat;
+
+
+
+// The matchers the walk classifies entries with, their match vectors are kept on the entries for the outputs
+pub type FileMatcher<'a> = &'a dyn Fn(&FileInfo) -> (bool, Vec<bool>);
+pub type DirMatcher<'a> = &'a dyn Fn(&DirInfo) -> (bool, Vec<bool>);
+
+
+/// The modified, created and accessed timestamps of an entry, None when the platform or filesystem doesn't record one.
+pub fn timestamps(metadata: Option<&fs::Metadata>) -> [(&'static str, Option<String>); 3] {
+	let fmt = |t: std::io::Result<SystemTime>| t.ok().map(|t| Into::<DateTime<Utc>>::into(t).to_rfc3339_opts(SecondsFormat::Secs, true));
+	[
+		("modified", metadata.and_then(|m| fmt(m.modified()))),
+		("created", metadata.and_then(|m| fmt(m.created()))),
+		("accessed", metadata.and_then(|m| fmt(m.accessed()))),
+	]
+}
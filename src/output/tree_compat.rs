@@ -0,0 +1,76 @@
+use std::io::Write;
+use anyhow::Result;
+use serde_json::{json, Value};
+
+use crate::{Args, TreeEntry, ordered_entries};
+use crate::tree::{Tree, DirId, ROOT};
+
+
+
+/*
+	Output structurally compatible with GNU tree's -X and -J so t can replace it in pipelines that parse those.
+	The listed entries are the matched ones, so the report counts are the p_total_* ones. Entries that couldn't be
+	read get an error child like GNU tree gives a directory it can't open.
+ */
+
+fn escape(s: &str) -> String {
+	s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&apos;")
+}
+
+/// Prints the filtered tree like `tree -X`.
+pub fn print_xml(tree: &Tree, args: &Args) -> Result<()> {
+	let mut out = std::io::stdout().lock();
+	writeln!(out, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<tree>")?;
+	writeln!(out, "  <directory name=\"{}\" size=\"{}\">", escape(&args.path.to_string_lossy()), tree.root().p_total_size(args.apparent_size))?;
+	write_xml(&mut out, tree, ROOT, "    ", args)?;
+	writeln!(out, "  </directory>")?;
+	let root = tree.root();
+	writeln!(out, "  <report>\n    <directories>{}</directories>\n    <files>{}</files>\n  </report>\n</tree>", root.p_total_dirs, root.p_total_files)?;
+	Ok(())
+}
+
+fn write_xml(out: &mut impl Write, tree: &Tree, dir: DirId, indent: &str, args: &Args) -> Result<()> {
+	for entry in ordered_entries(tree, dir, args) {
+		match entry {
+			TreeEntry::File(file) => {
+				let file = &tree.files[file];
+				let error = file.error.as_ref().map(|e| format!("<error>{}</error>", escape(e))).unwrap_or_default();
+				writeln!(out, "{}<file name=\"{}\" size=\"{}\">{}</file>", indent, escape(&file.name), file.size(args.apparent_size), error)?;
+			}
+			TreeEntry::Dir(subdir) => {
+				let d = &tree.dirs[subdir];
+				writeln!(out, "{}<directory name=\"{}\" size=\"{}\">", indent, escape(&d.name), d.p_total_size(args.apparent_size))?;
+				if let Some(e) = &d.error { writeln!(out, "{}  <error>{}</error>", indent, escape(e))?; }
+				write_xml(out, tree, subdir, &format!("{}  ", indent), args)?;
+				writeln!(out, "{}</directory>", indent)?;
+			}
+		}
+	}
+	Ok(())
+}
+
+fn tree_json(tree: &Tree, id: DirId, name: String, args: &Args) -> Value {
+	let dir = &tree.dirs[id];
+	let mut contents: Vec<Value> = dir.error.iter().map(|e| json!({ "error": e })).collect();
+	contents.extend(ordered_entries(tree, id, args).into_iter().map(|e| match e {
+		TreeEntry::File(f) => {
+			let f = &tree.files[f];
+			match &f.error {
+				Some(e) => json!({ "type": "file", "name": f.name, "size": f.size(args.apparent_size), "error": e }),
+				None => json!({ "type": "file", "name": f.name, "size": f.size(args.apparent_size) }),
+			}
+		}
+		TreeEntry::Dir(d) => tree_json(tree, d, tree.dirs[d].name.clone(), args),
+	}));
+	json!({ "type": "directory", "name": name, "size": dir.p_total_size(args.apparent_size), "contents": contents })
+}
+
+/// Prints the filtered tree like `tree -J`.
+pub fn print_tree_json(tree: &Tree, args: &Args) -> Result<()> {
+	let report = { let root = tree.root(); json!({ "type": "report", "directories": root.p_total_dirs, "files": root.p_total_files }) };
+	let doc = json!([ tree_json(tree, ROOT, args.path.to_string_lossy().to_string(), args), report ]);
+	let mut out = std::io::stdout().lock();
+	serde_json::to_writer_pretty(&mut out, &doc)?;
+	writeln!(out)?;
+	Ok(())
+}
@@ -0,0 +1,94 @@
+use std::cmp::Ordering;
+use std::time::SystemTime;
+
+
+
+/// The value an entry is sorted by for --sort.
+#[derive(Debug, PartialEq, PartialOrd)]
+pub enum SortKey {
+	Num(u64),
+	Time(Option<SystemTime>),
+	Text(String),
+}
+
+/// Compares names, optionally folding case and/or comparing runs of digits by value so `file2` comes before `file10`
+/// and `v1.9` before `v1.10`. Names that only differ in case are still ordered, by the case sensitive comparison.
+pub fn compare_names(a: &str, b: &str, natural: bool, ignore_case: bool) -> Ordering {
+	if ignore_case {
+		return compare(&a.to_lowercase(), &b.to_lowercase(), natural).then_with(|| compare(a, b, natural));
+	}
+	compare(a, b, natural)
+}
+
+fn compare(a: &str, b: &str, natural: bool) -> Ordering {
+	if !natural { return a.cmp(b); }
+
+	let (mut a, mut b) = (a, b);
+	loop {
+		match (a.is_empty(), b.is_empty()) {
+			(true, true) => return Ordering::Equal,
+			(true, false) => return Ordering::Less,
+			(false, true) => return Ordering::Greater,
+			_ => {}
+		}
+
+		let a_digits = a.starts_with(|c: char| c.is_ascii_digit());
+		let b_digits = b.starts_with(|c: char| c.is_ascii_digit());
+		let split = |s: &str, digits: bool| s.find(|c: char| c.is_ascii_digit() != digits).unwrap_or(s.len());
+		let (a_run, a_rest) = a.split_at(split(a, a_digits));
+		let (b_run, b_rest) = b.split_at(split(b, b_digits));
+
+		let ord = if a_digits && b_digits {
+			// Compare by value without parsing so long runs can't overflow: fewer significant digits is smaller
+			let (a_num, b_num) = (a_run.trim_start_matches('0'), b_run.trim_start_matches('0'));
+			a_num.len().cmp(&b_num.len()).then_with(|| a_num.cmp(b_num)).then_with(|| a_run.len().cmp(&b_run.len()))
+		} else {
+			a_run.cmp(b_run)
+		};
+		if ord != Ordering::Equal { return ord; }
+
+		a = a_rest;
+		b = b_rest;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn sorted(names: &[&str], natural: bool, ignore_case: bool) -> Vec<String> {
+		let mut names: Vec<String> = names.iter().map(|s| s.to_string()).collect();
+		names.sort_by(|a, b| compare_names(a, b, natural, ignore_case));
+		names
+	}
+
+	#[test]
+	fn plain_is_byte_order() {
+		assert_eq!(sorted(&["b", "B", "a", "A"], false, false), ["A", "B", "a", "b"]);
+		assert_eq!(sorted(&["f10", "f2", "f1"], false, false), ["f1", "f10", "f2"]);
+	}
+
+	#[test]
+	fn ignore_case_ties_fall_back_to_case_sensitive() {
+		assert_eq!(sorted(&["b", "B", "a", "A"], false, true), ["A", "a", "B", "b"]);
+		assert_eq!(sorted(&["readme", "README", "ReadMe"], false, true), ["README", "ReadMe", "readme"]);
+		assert_eq!(compare_names("Makefile", "makefile", false, true), Ordering::Less);
+		assert_eq!(compare_names("makefile", "Makefile", false, true), Ordering::Greater);
+		assert_eq!(compare_names("same", "same", false, true), Ordering::Equal);
+	}
+
+	#[test]
+	fn natural_compares_digit_runs_by_value() {
+		assert_eq!(sorted(&["f10", "f2", "f1"], true, false), ["f1", "f2", "f10"]);
+		assert_eq!(sorted(&["v1.10", "v1.9", "v1.1"], true, false), ["v1.1", "v1.9", "v1.10"]);
+		// Equal values with more leading zeros come after
+		assert_eq!(sorted(&["f001", "f1", "f01"], true, false), ["f1", "f01", "f001"]);
+		// Runs longer than any integer type
+		assert_eq!(compare_names("x99999999999999999999999", "x100000000000000000000000", true, false), Ordering::Less);
+	}
+
+	#[test]
+	fn natural_and_ignore_case_together() {
+		assert_eq!(sorted(&["F10", "f2", "F2", "f1"], true, true), ["f1", "F2", "f2", "F10"]);
+	}
+}
@@ -0,0 +1,161 @@
+use std::fs;
+use std::io::Write;
+use std::rc::Rc;
+use anyhow::Result;
+use colored::*;
+
+use crate::{DirInfo, FileInfo, Order, SortBy, DEFAULT_DIR_FORMAT, compare_entries, dim_ignored, dir_sort_key, error_message, file_sort_key,
+	format_string, get_combined_color, get_dir_format_values, get_file_format_values, get_format_string, info_or_error, merge_sorted,
+	WalkContext};
+use crate::ignore_rules::IgnoreRules;
+use crate::tree::ROOT;
+
+
+
+/*
+	--stream: print matched entries as the walk finds them instead of building the tree first
+
+	Only the directory being listed is held, so memory doesn't grow with the tree and output starts immediately.
+	Entries are printed as paths relative to the start directory: whether an entry is the last one shown in its directory,
+	which a tree connector needs, isn't known until everything after it has been walked. What's given up are the tokens
+	that need a whole subtree (%total_size%, %p_total_files%, ...) which are left empty.
+ */
+
+// Directory tokens that are totals over the subtree
+const AGGREGATE_TOKENS: &[&str] = &["size", "alloc_size", "apparent_size", "immediate_files_size", "total_size", "total_files", "total_dirs",
+	"p_immediate_files_size", "p_total_size", "p_total_files", "p_total_dirs", "immediate_files_apparent_size", "total_apparent_size",
+	"p_immediate_files_apparent_size", "p_total_apparent_size", "sub_dirs_count", "sub_files_count"];
+
+enum StreamEntry {
+	File(FileInfo),
+	Dir(DirInfo),
+}
+
+/// Walks and prints the tree. Returns the root with the running totals the summary needs, and no children.
+pub fn stream_tree(root_metadata: fs::Metadata, ctx: &WalkContext, ignores: Option<Rc<IgnoreRules>>) -> Result<DirInfo> {
+	let args = ctx.args;
+	let root = DirInfo::new(&args.path, 0, None, false, Some(root_metadata));
+	let mut totals = DirInfo::new(&args.path, 0, None, false, None);
+	let mut out = std::io::stdout().lock();
+	stream_directory(&root, &mut totals, ctx, ignores, &mut out)?;
+	Ok(totals)
+}
+
+fn stream_directory(dir: &DirInfo, totals: &mut DirInfo, ctx: &WalkContext, ignores: Option<Rc<IgnoreRules>>, out: &mut impl Write) -> Result<bool> {
+	let WalkContext { dir_matcher, prune_matcher, dir_excluder, args, walker, .. } = *ctx;
+	// Not read when excluded, past --depth, nor when --prune-dirs doesn't descend into it (the root is always walked)
+	let listing = if dir.excluded {
+		walker.skip(&dir.path);
+		None
+	} else if dir.depth >= args.depth {
+		None
+	} else if args.prune_dirs && dir.depth > 0 && !prune_matcher(dir) {
+		totals.pruned_dirs += 1;
+		walker.skip(&dir.path);
+		None
+	} else {
+		match walker.list(&dir.path) {
+			Err(e) if dir.depth == 0 => anyhow::bail!("Can't read '{}': {}", args.path.display(), error_message(&e)),
+			listing => Some(listing),
+		}
+	};
+
+	let ignores = match &listing {
+		Some(Ok(listing)) => ctx.dir_ignores(dir, ignores, listing),
+		_ => None,
+	};
+	let mut error = None;
+	let (dir_entries, file_entries) = match listing {
+		Some(Ok(listing)) => ctx.split_listing(listing, dir.ignored, &ignores, &mut error),
+		Some(Err(e)) => { error = Some(error_message(&e)); Default::default() }
+		None => Default::default(),
+	};
+	totals.total_errors += error.is_some() as u64;
+
+	// The directory's own line, now that it's known whether it could be read
+	let rel = |p: &std::path::Path| p.strip_prefix(&args.path).unwrap_or(p).to_string_lossy().to_string();
+	if dir.depth > 0 && (dir.regex_matched || error.is_some()) {
+		let dformat_str = get_format_string(args, true);
+		let dformat_str = if dformat_str == DEFAULT_DIR_FORMAT { String::new() } else { dformat_str };
+		let mut values = get_dir_format_values(dir, args);
+		for token in AGGREGATE_TOKENS { values.insert(*token, String::new()); }
+		let info = format_string(&dformat_str, &values, true);
+		let name = format!("{}/", rel(&dir.path));
+		writeln!(out, "{} {}", dim_ignored(get_combined_color(&name, &dir.matches, true), dir.ignored), info_or_error(info, &error))?;
+	}
+	if dir_entries.is_empty() && file_entries.is_empty() { return Ok(false); }
+
+	if dir.depth + 1 < args.depth {
+		walker.prefetch(dir_entries.iter().map(|(e, _)| e.path.clone()).collect());
+	}
+
+	// Matched files and the ones that couldn't be stat'ed, counting every file into the totals
+	let mut files = Vec::new();
+	for (entry, entry_ignored) in file_entries {
+		let file = ctx.file_info(entry, entry_ignored, ROOT);
+
+		totals.total_files += 1;
+		totals.total_size += file.size;
+		totals.total_apparent_size += file.apparent_size;
+		totals.total_errors += file.error.is_some() as u64;
+		if !file.regex_matched {
+			if file.error.is_some() { files.push(file); }
+			continue;
+		}
+		totals.p_total_files += 1;
+		totals.p_total_size += file.size;
+		totals.p_total_apparent_size += file.apparent_size;
+		files.push(file);
+	}
+
+	// Every subdirectory, they're walked whether or not they match themselves unless excluded
+	let mut dirs = Vec::new();
+	for (entry, entry_ignored) in dir_entries {
+		let mut subdir = DirInfo::new(&entry.path, dir.depth + 1, None, entry_ignored, entry.metadata.ok());
+		subdir.excluded = dir_excluder(&subdir);
+		let (matched, matches) = dir_matcher(&subdir);
+		subdir.regex_matched = !subdir.excluded && matched;
+		subdir.matches = matches;
+		totals.total_dirs += 1;
+		dirs.push(subdir);
+	}
+
+	let any_files = files.iter().any(|f| f.regex_matched);
+
+	// Sorted like the tree, but keys that need the subtree's totals can't be known yet (see main). --sort size only orders
+	// the files, directories keep their name order
+	let dir_key = |d: &DirInfo| if args.sort == SortBy::Size { None } else { dir_sort_key(d, args) };
+	let keyed_files = files.into_iter().map(|f| (file_sort_key(&f, args), f.path.clone(), StreamEntry::File(f)));
+	let keyed_dirs = dirs.into_iter().map(|d| (dir_key(&d), d.path.clone(), StreamEntry::Dir(d)));
+	let mut keyed_files: Vec<_> = keyed_files.collect();
+	let mut keyed_dirs: Vec<_> = keyed_dirs.collect();
+	keyed_files.sort_by(|a, b| compare_entries((&a.0, &a.1), (&b.0, &b.1), args));
+	keyed_dirs.sort_by(|a, b| compare_entries((&a.0, &a.1), (&b.0, &b.1), args));
+	let ordered = match args.order {
+		Order::FilesFirst => keyed_files.into_iter().chain(keyed_dirs).map(|e| e.2).collect(),
+		Order::DirsFirst => keyed_dirs.into_iter().chain(keyed_files).map(|e| e.2).collect(),
+		Order::Mixed => merge_sorted(keyed_files, keyed_dirs, args),
+	};
+
+	let fformat_str = get_format_string(args, false);
+
+	// Whether anything below matched, which is what keeps a directory in the tree
+	let mut contains_match = any_files;
+	for entry in ordered {
+		match entry {
+			StreamEntry::File(file) => {
+				let info = format_string(&fformat_str, &get_file_format_values(&file, args), false);
+				writeln!(out, "{} {}", dim_ignored(get_combined_color(&rel(&file.path), &file.matches, false), file.ignored), info_or_error(info, &file.error))?;
+			}
+			StreamEntry::Dir(subdir) => {
+				let below = stream_directory(&subdir, totals, ctx, ignores.clone(), out)?;
+				if subdir.regex_matched || below {
+					totals.p_total_dirs += 1;
+					contains_match = true;
+				}
+			}
+		}
+	}
+
+	Ok(contains_match)
+}
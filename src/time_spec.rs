@@ -0,0 +1,218 @@
+use std::time::{Duration, SystemTime};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+
+
+
+/// A point or period in time used by the modified/created/accessed predicates.
+///
+/// Periods are half open `[start, end)`: `2025-01` covers all of January and `2025-01-15` all of that day (UTC, like the
+/// %modified% tokens). A relative age such as `7d` is the single instant that long ago.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeSpec {
+	Ago(SystemTime),
+	Period(SystemTime, SystemTime),
+}
+
+/// Why a time value was rejected.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeError {
+	Invalid,		// not an age, date or range
+	OutOfRange,		// an age too large to represent or to go back from now
+}
+
+/// Parses an age like `30s`, `15m`, `3h`, `7d`, `2w`, `6mo` or `1y` (months are 30 days and years 365 days).
+pub fn parse_duration(s: &str) -> Result<Duration, TimeError> {
+	let s = s.trim();
+	let split = s.find(|c: char| !(c.is_ascii_digit() || c == '.')).ok_or(TimeError::Invalid)?;
+	let (number, unit) = s.split_at(split);
+	let number: f64 = number.parse().map_err(|_| TimeError::Invalid)?;
+	let seconds = match unit.to_lowercase().as_str() {
+		"s" | "sec" | "secs" => 1.0,
+		"m" | "min" | "mins" => 60.0,
+		"h" | "hour" | "hours" => 3600.0,
+		"d" | "day" | "days" => 86400.0,
+		"w" | "week" | "weeks" => 7.0 * 86400.0,
+		"mo" | "month" | "months" => 30.0 * 86400.0,
+		"y" | "year" | "years" => 365.0 * 86400.0,
+		_ => return Err(TimeError::Invalid),
+	};
+	Duration::try_from_secs_f64(number * seconds).map_err(|_| TimeError::OutOfRange)
+}
+
+fn to_system_time(dt: NaiveDateTime) -> SystemTime {
+	DateTime::<Utc>::from_naive_utc_and_offset(dt, Utc).into()
+}
+
+// The first day of the month after year/month
+fn next_month(year: i32, month: u32) -> Option<NaiveDate> {
+	if month == 12 { NaiveDate::from_ymd_opt(year + 1, 1, 1) } else { NaiveDate::from_ymd_opt(year, month + 1, 1) }
+}
+
+/// Parses an absolute date as the period it names: `2025`, `2025-03`, `2025-03-14`, `2025-03-14T09:30`, `2025-03-14 09:30:15`
+/// or a full RFC 3339 timestamp.
+pub fn parse_period(s: &str) -> Option<(SystemTime, SystemTime)> {
+	let s = s.trim();
+
+	if let Ok(dt) = DateTime::parse_from_rfc3339(s) {
+		let t: SystemTime = dt.into();
+		return Some((t, t + Duration::from_secs(1)));
+	}
+
+	let parts: Vec<&str> = s.split('-').collect();
+	let day_time = |fmt: &str, len: Duration| NaiveDateTime::parse_from_str(s, fmt).ok().map(|dt| (to_system_time(dt), to_system_time(dt) + len));
+	if let Some(p) = day_time("%Y-%m-%dT%H:%M:%S", Duration::from_secs(1)).or_else(|| day_time("%Y-%m-%d %H:%M:%S", Duration::from_secs(1)))
+		.or_else(|| day_time("%Y-%m-%dT%H:%M", Duration::from_secs(60))).or_else(|| day_time("%Y-%m-%d %H:%M", Duration::from_secs(60))) {
+		return Some(p);
+	}
+
+	let (start, end) = match parts.len() {
+		1 => {
+			let year: i32 = parts[0].parse().ok()?;
+			(NaiveDate::from_ymd_opt(year, 1, 1)?, NaiveDate::from_ymd_opt(year + 1, 1, 1)?)
+		}
+		2 => {
+			let year: i32 = parts[0].parse().ok()?;
+			let month: u32 = parts[1].parse().ok()?;
+			(NaiveDate::from_ymd_opt(year, month, 1)?, next_month(year, month)?)
+		}
+		3 => {
+			let day = NaiveDate::parse_from_str(s, "%Y-%m-%d").ok()?;
+			(day, day.succ_opt()?)
+		}
+		_ => return None,
+	};
+	// reject things like "7" being taken as a year by requiring 4 digits
+	if parts[0].len() != 4 { return None; }
+	Some((to_system_time(start.and_hms_opt(0, 0, 0)?), to_system_time(end.and_hms_opt(0, 0, 0)?)))
+}
+
+/// Parses a relative age or an absolute date.
+pub fn parse_time_spec(s: &str, now: SystemTime) -> Result<TimeSpec, TimeError> {
+	match parse_duration(s) {
+		Ok(d) => now.checked_sub(d).map(TimeSpec::Ago).ok_or(TimeError::OutOfRange),
+		Err(TimeError::OutOfRange) => Err(TimeError::OutOfRange),
+		Err(TimeError::Invalid) => parse_period(s).map(|(a, b)| TimeSpec::Period(a, b)).ok_or(TimeError::Invalid),
+	}
+}
+
+/// `[start, end)`, either end open.
+pub type TimeRange = (Option<SystemTime>, Option<SystemTime>);
+
+/// Parses a `from..to` range, either side may be empty or a relative age (`30d..7d`). Returns `[start, end)`, or None if
+/// `s` isn't a range at all.
+pub fn parse_time_range(s: &str, now: SystemTime) -> Result<Option<TimeRange>, TimeError> {
+	let Some((from, to)) = s.split_once("..") else { return Ok(None) };
+	let bound = |b: &str, upper: bool| -> Result<Option<SystemTime>, TimeError> {
+		if b.trim().is_empty() { return Ok(None); }
+		match parse_time_spec(b, now)? {
+			TimeSpec::Ago(t) => Ok(Some(t)),
+			TimeSpec::Period(start, end) => Ok(Some(if upper { end } else { start })),
+		}
+	};
+	let (from, to) = (bound(from, false)?, bound(to, true)?);
+
+	// "30d..7d" reads naturally but names the later instant second, so order the bounds
+	match (from, to) {
+		(Some(a), Some(b)) if a > b => Ok(Some((Some(b), Some(a)))),
+		r => Ok(Some(r)),
+	}
+}
+
+/// `time < spec`: newer than an age (`<:7d` is "less than 7 days old") or before a date.
+pub fn time_less(t: SystemTime, spec: TimeSpec) -> bool {
+	match spec {
+		TimeSpec::Ago(point) => t > point,
+		TimeSpec::Period(start, _) => t < start,
+	}
+}
+
+/// `time > spec`: older than an age or after a date.
+pub fn time_greater(t: SystemTime, spec: TimeSpec) -> bool {
+	match spec {
+		TimeSpec::Ago(point) => t < point,
+		TimeSpec::Period(_, end) => t >= end,
+	}
+}
+
+/// `time = spec`: within the named period. Ages are instants so are compared to the second.
+pub fn time_equals(t: SystemTime, spec: TimeSpec) -> bool {
+	match spec {
+		TimeSpec::Ago(point) => {
+			let d = if t > point { t.duration_since(point) } else { point.duration_since(t) };
+			d.is_ok_and(|d| d < Duration::from_secs(1))
+		}
+		TimeSpec::Period(start, end) => t >= start && t < end,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn date(s: &str) -> SystemTime {
+		to_system_time(NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").unwrap())
+	}
+
+	#[test]
+	fn relative_specs() {
+		let now = date("2025-06-15 12:00:00");
+		assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+		assert_eq!(parse_duration("15min"), Ok(Duration::from_secs(15 * 60)));
+		assert_eq!(parse_duration("1.5h"), Ok(Duration::from_secs(5400)));
+		assert_eq!(parse_duration("2w"), Ok(Duration::from_secs(14 * 86400)));
+		assert_eq!(parse_duration("6mo"), Ok(Duration::from_secs(180 * 86400)));
+		assert_eq!(parse_time_spec("7d", now), Ok(TimeSpec::Ago(date("2025-06-08 12:00:00"))));
+		assert_eq!(parse_time_spec("1y", now), Ok(TimeSpec::Ago(date("2024-06-15 12:00:00"))));
+		assert_eq!(parse_duration("7"), Err(TimeError::Invalid));
+		assert_eq!(parse_duration("7x"), Err(TimeError::Invalid));
+		assert_eq!(parse_duration("d"), Err(TimeError::Invalid));
+	}
+
+	#[test]
+	fn huge_ages_are_out_of_range() {
+		let now = date("2025-06-15 12:00:00");
+		assert_eq!(parse_duration("99999999999999999999y"), Err(TimeError::OutOfRange));
+		assert_eq!(parse_time_spec("99999999999999999999y", now), Err(TimeError::OutOfRange));
+		assert_eq!(parse_time_range("99999999999999999999y..7d", now), Err(TimeError::OutOfRange));
+	}
+
+	#[test]
+	fn absolute_dates() {
+		let now = date("2025-06-15 12:00:00");
+		assert_eq!(parse_period("2025"), Some((date("2025-01-01 00:00:00"), date("2026-01-01 00:00:00"))));
+		assert_eq!(parse_period("2025-12"), Some((date("2025-12-01 00:00:00"), date("2026-01-01 00:00:00"))));
+		assert_eq!(parse_period("2024-02-29"), Some((date("2024-02-29 00:00:00"), date("2024-03-01 00:00:00"))));
+		assert_eq!(parse_period("2025-03-14T09:30"), Some((date("2025-03-14 09:30:00"), date("2025-03-14 09:31:00"))));
+		assert_eq!(parse_period("2025-03-14 09:30:15"), Some((date("2025-03-14 09:30:15"), date("2025-03-14 09:30:16"))));
+		assert_eq!(parse_period("2025-03-14T09:30:15Z"), Some((date("2025-03-14 09:30:15"), date("2025-03-14 09:30:16"))));
+		assert_eq!(parse_period("2025-13"), None);
+		assert_eq!(parse_period("2025-02-30"), None);
+		assert_eq!(parse_period("25"), None);
+		assert_eq!(parse_time_spec("yesterday", now), Err(TimeError::Invalid));
+	}
+
+	#[test]
+	fn ranges() {
+		let now = date("2025-06-15 12:00:00");
+		assert_eq!(parse_time_range("2025-01..2025-03", now), Ok(Some((Some(date("2025-01-01 00:00:00")), Some(date("2025-04-01 00:00:00"))))));
+		assert_eq!(parse_time_range("..2025", now), Ok(Some((None, Some(date("2026-01-01 00:00:00"))))));
+		assert_eq!(parse_time_range("2025-06-01..", now), Ok(Some((Some(date("2025-06-01 00:00:00")), None))));
+		// Ages are ordered so the older one is the start
+		assert_eq!(parse_time_range("30d..7d", now), Ok(Some((Some(date("2025-05-16 12:00:00")), Some(date("2025-06-08 12:00:00"))))));
+		assert_eq!(parse_time_range("7d..30d", now), parse_time_range("30d..7d", now));
+		assert_eq!(parse_time_range("2025-01", now), Ok(None));
+		assert_eq!(parse_time_range("2025-01..nope", now), Err(TimeError::Invalid));
+	}
+
+	#[test]
+	fn comparisons() {
+		let spec = TimeSpec::Period(date("2025-03-01 00:00:00"), date("2025-04-01 00:00:00"));
+		assert!(time_equals(date("2025-03-31 23:59:59"), spec));
+		assert!(!time_equals(date("2025-04-01 00:00:00"), spec));
+		assert!(time_less(date("2025-02-28 00:00:00"), spec));
+		assert!(time_greater(date("2025-04-01 00:00:00"), spec));
+		let week_ago = TimeSpec::Ago(date("2025-06-08 12:00:00"));
+		assert!(time_less(date("2025-06-10 00:00:00"), week_ago));
+		assert!(time_greater(date("2025-06-01 00:00:00"), week_ago));
+	}
+}
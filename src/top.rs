@@ -0,0 +1,58 @@
+use std::path::Path;
+
+use crate::{Args, fmt_size, is_kept};
+use crate::tree::{Tree, ROOT};
+
+
+
+/// Which entries --top reports.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum TopKind {
+	/// Only the largest files
+	Files,
+	/// Only the largest directories
+	Dirs,
+	/// Both, files first
+	Both,
+}
+
+// Largest first, ties by path so the report is stable
+fn section(title: &str, mut entries: Vec<(u64, String)>, n: usize, total: u64, args: &Args) -> Vec<String> {
+	entries.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+	entries.truncate(n);
+
+	let sizes: Vec<String> = entries.iter().map(|(size, _)| fmt_size(*size, args)).collect();
+	let width = sizes.iter().map(|s| s.len()).max().unwrap_or(0);
+
+	let mut lines = vec![format!("{}:", title)];
+	if entries.is_empty() { lines.push("  (none)".to_string()); }
+	for ((size, path), size_str) in entries.iter().zip(&sizes) {
+		let percent = if total == 0 { 0.0 } else { *size as f64 * 100.0 / total as f64 };
+		lines.push(format!("  {:>width$}  {:>5.1}%  {}", size_str, percent, path, width = width));
+	}
+	lines
+}
+
+/// The --top report: the largest matched files and/or directories with their share of the (matched) total size.
+pub fn top_lines(tree: &Tree, n: usize, kind: TopKind, filtered: bool, args: &Args) -> Vec<String> {
+	let root = tree.root();
+	let total = if filtered { root.p_total_size(args.apparent_size) } else { root.total_size(args.apparent_size) };
+
+	// (size, path relative to the start directory) of every matched file and directory below the root. Files that couldn't
+	// be read have no size to rank
+	let rel = |p: &Path| p.strip_prefix(&root.path).unwrap_or(p).to_string_lossy().to_string();
+	let files = tree.files.iter().filter(|f| f.regex_matched && f.error.is_none()).map(|f| (f.size(args.apparent_size), rel(&f.path))).collect();
+	let dirs = tree.dirs.iter().enumerate().filter(|(id, d)| *id != ROOT && is_kept(d)).map(|(_, d)| {
+		(if filtered { d.p_total_size(args.apparent_size) } else { d.total_size(args.apparent_size) }, rel(&d.path))
+	}).collect();
+
+	let mut lines = Vec::new();
+	if kind != TopKind::Dirs {
+		lines.extend(section(&format!("Largest {} files", n), files, n, total, args));
+	}
+	if kind != TopKind::Files {
+		if !lines.is_empty() { lines.push(String::new()); }
+		lines.extend(section(&format!("Largest {} directories", n), dirs, n, total, args));
+	}
+	lines
+}
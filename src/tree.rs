@@ -0,0 +1,80 @@
+use std::collections::VecDeque;
+
+use crate::{DirInfo, FileInfo};
+
+
+
+/*
+	The directory tree as two flat arenas, one for directories and one for files
+
+	Nodes refer to each other by index: every node knows its parent's id and a directory's children are the
+	contiguous ranges `sub_dirs` and `sub_files` of the arenas. A child always has a larger id than its parent,
+	so walking the directories backwards visits every subtree before the directory that contains it.
+ */
+
+pub type DirId = usize;
+pub type FileId = usize;
+
+/// The id of the start directory.
+pub const ROOT: DirId = 0;
+
+#[derive(Debug)]
+pub struct Tree {
+	pub dirs: Vec<DirInfo>,
+	pub files: Vec<FileInfo>,
+}
+
+impl Tree {
+	/// A tree holding only the start directory.
+	pub fn new(root: DirInfo) -> Tree {
+		Tree { dirs: vec![root], files: Vec::new() }
+	}
+
+	pub fn root(&self) -> &DirInfo { &self.dirs[ROOT] }
+
+	/// The ids of the directories above `id`, nearest first.
+	pub fn ancestors(&self, id: DirId) -> impl Iterator<Item = DirId> + '_ {
+		std::iter::successors(self.dirs[id].parent, move |&p| self.dirs[p].parent)
+	}
+
+	/// Rebuilds the tree with, for each kept directory, the children `children` returns in the order it returns them.
+	/// Children that aren't returned are dropped with everything below them.
+	pub fn rebuild(self, mut children: impl FnMut(&Tree, DirId) -> (Vec<FileId>, Vec<DirId>)) -> Tree {
+		// Decide the layout first, breadth first so each directory's children end up next to each other
+		let mut layout = Vec::new();			// (old id, old child file ids, old child dir ids) in new id order
+		let mut queue = VecDeque::from([ROOT]);
+		while let Some(id) = queue.pop_front() {
+			let (files, dirs) = children(&self, id);
+			queue.extend(dirs.iter().copied());
+			layout.push((id, files, dirs));
+		}
+
+		let mut old_dirs: Vec<Option<DirInfo>> = self.dirs.into_iter().map(Some).collect();
+		let mut old_files: Vec<Option<FileInfo>> = self.files.into_iter().map(Some).collect();
+		let mut tree = Tree { dirs: Vec::with_capacity(layout.len()), files: Vec::new() };
+
+		// New ids are handed out in the same breadth first order, the next child directory gets next_dir
+		let mut next_dir = 1;
+		for (new_id, (old_id, files, dirs)) in layout.into_iter().enumerate() {
+			let mut dir = old_dirs[old_id].take().unwrap();
+			if new_id == ROOT { dir.parent = None; }
+
+			let first_file = tree.files.len();
+			for f in files {
+				let mut file = old_files[f].take().unwrap();
+				file.parent = new_id;
+				tree.files.push(file);
+			}
+			dir.sub_files = first_file..tree.files.len();
+			dir.sub_dirs = next_dir..next_dir + dirs.len();
+			next_dir += dirs.len();
+			tree.dirs.push(dir);
+		}
+
+		// Point the moved directories at their parent's new id
+		for id in 0..tree.dirs.len() {
+			for child in tree.dirs[id].sub_dirs.clone() { tree.dirs[child].parent = Some(id); }
+		}
+		tree
+	}
+}
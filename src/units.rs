@@ -0,0 +1,114 @@
+/// How sizes are rendered in format tokens and the summary.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SizeUnits {
+	Bytes,		// raw byte counts
+	Binary,		// powers of 1024: KiB, MiB, ...
+	Si,			// powers of 1000: kB, MB, ...
+}
+
+const BINARY_SUFFIXES: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+const SI_SUFFIXES: &[&str] = &["B", "kB", "MB", "GB", "TB", "PB", "EB"];
+
+/// Renders `bytes` in the given units with `precision` decimals once scaled.
+pub fn format_size(bytes: u64, units: SizeUnits, precision: usize) -> String {
+	let (base, suffixes) = match units {
+		SizeUnits::Bytes => return bytes.to_string(),
+		SizeUnits::Binary => (1024.0, BINARY_SUFFIXES),
+		SizeUnits::Si => (1000.0, SI_SUFFIXES),
+	};
+
+	let mut value = bytes as f64;
+	let mut i = 0;
+	while value >= base && i < suffixes.len() - 1 {
+		value /= base;
+		i += 1;
+	}
+
+	if i == 0 { return format!("{} {}", bytes, suffixes[0]); }
+	format!("{:.*} {}", precision, value, suffixes[i])
+}
+
+/// Parses a size such as `1024`, `10K`, `1.5MiB`, `2G` or `3kB` into bytes.
+///
+/// Like GNU tools, a bare letter or the `iB` form is a power of 1024 and the `B` form (`KB`, `MB`) a power of 1000.
+pub fn parse_size(s: &str) -> Option<u64> {
+	let s = s.trim();
+	let split = s.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(s.len());
+	let (number, suffix) = s.split_at(split);
+	let number: f64 = number.parse().ok()?;
+
+	let suffix = suffix.trim().to_lowercase();
+	let (letter, rest) = match suffix.chars().next() {
+		Some(c) => (Some(c), &suffix[c.len_utf8()..]),
+		None => (None, ""),
+	};
+	let power = match letter {
+		None | Some('b') if rest.is_empty() => 0,
+		Some('k') => 1,
+		Some('m') => 2,
+		Some('g') => 3,
+		Some('t') => 4,
+		Some('p') => 5,
+		Some('e') => 6,
+		_ => return None,
+	};
+	let base: f64 = match rest {
+		"" | "i" | "ib" => 1024.0,
+		"b" => 1000.0,
+		_ => return None,
+	};
+
+	Some((number * base.powi(power)).round() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parse_size_plain_bytes() {
+		assert_eq!(parse_size("1024"), Some(1024));
+		assert_eq!(parse_size("0"), Some(0));
+		assert_eq!(parse_size("12b"), Some(12));
+		assert_eq!(parse_size(" 7 "), Some(7));
+	}
+
+	#[test]
+	fn parse_size_binary_suffixes() {
+		assert_eq!(parse_size("10K"), Some(10 * 1024));
+		assert_eq!(parse_size("10k"), Some(10 * 1024));
+		assert_eq!(parse_size("10Ki"), Some(10 * 1024));
+		assert_eq!(parse_size("10KiB"), Some(10 * 1024));
+		assert_eq!(parse_size("2M"), Some(2 * 1024 * 1024));
+		assert_eq!(parse_size("3MiB"), Some(3 * 1024 * 1024));
+		assert_eq!(parse_size("1G"), Some(1 << 30));
+		assert_eq!(parse_size("1T"), Some(1 << 40));
+	}
+
+	#[test]
+	fn parse_size_si_suffixes() {
+		assert_eq!(parse_size("3kB"), Some(3000));
+		assert_eq!(parse_size("3KB"), Some(3000));
+		assert_eq!(parse_size("2MB"), Some(2_000_000));
+		assert_eq!(parse_size("10 KB"), Some(10_000));
+	}
+
+	#[test]
+	fn parse_size_decimals() {
+		assert_eq!(parse_size("1.5MiB"), Some(1_572_864));
+		assert_eq!(parse_size("0.5K"), Some(512));
+		assert_eq!(parse_size("1.5kB"), Some(1500));
+	}
+
+	#[test]
+	fn parse_size_rejects_garbage() {
+		assert_eq!(parse_size(""), None);
+		assert_eq!(parse_size("K"), None);
+		assert_eq!(parse_size("abc"), None);
+		assert_eq!(parse_size("1.2.3"), None);
+		assert_eq!(parse_size("10x"), None);
+		assert_eq!(parse_size("10KiBs"), None);
+		assert_eq!(parse_size("10µ"), None);
+		assert_eq!(parse_size("10kµ"), None);
+	}
+}
@@ -0,0 +1,155 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+
+use crate::disk_size::allocated_size;
+
+
+
+/*
+	Directory listing for build_directory_tree, optionally read ahead by a pool of --threads
+
+	The tree is still built by one sequential depth first walk, so it comes out identical whatever the thread count.
+	What the threads take off that walk is the I/O: whenever a directory is listed its subdirectories are queued and
+	idle workers list (and stat) them in the background. Workers take the most recently queued directory first,
+	which is the one the walk will reach next. When the walk needs a directory nobody has started yet it lists it
+	itself instead of waiting for a worker. Directories the walk then decides not to descend into (--prune-dirs) are
+	skipped so their listings aren't read, or kept around, for nothing.
+
+	This is read ahead rather than work stealing over directories: the listing is what's slow on big or network
+	backed trees, and keeping the tree building on one thread means the arena, the aggregates and the output order
+	don't depend on which worker got to what first.
+ */
+
+/// A directory entry with its metadata, stat'ed once.
+pub struct ListedEntry {
+	pub path: PathBuf,
+	pub name: String,
+	pub metadata: io::Result<fs::Metadata>,	// an error when the entry can't be stat'ed (e.g. it vanished)
+	pub size: u64,							// allocated size, see disk_size
+}
+
+impl ListedEntry {
+	pub fn is_dir(&self) -> bool { self.metadata.as_ref().is_ok_and(|m| m.is_dir()) }
+	pub fn apparent_size(&self) -> u64 { self.metadata.as_ref().map_or(0, |m| m.len()) }
+}
+
+/// The entries of a directory. The outer error is the directory being unreadable, the inner ones reading an entry.
+pub type Listing = io::Result<Vec<io::Result<ListedEntry>>>;
+
+fn list_dir(path: &Path) -> Listing {
+	Ok(fs::read_dir(path)?.map(|entry| {
+		let path = entry?.path();
+		let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+		// A dangling symlink isn't an error, it's listed as the link itself
+		let metadata = fs::metadata(&path).or_else(|e| fs::symlink_metadata(&path).ok().filter(|m| m.file_type().is_symlink()).ok_or(e));
+		let size = metadata.as_ref().map_or(0, |m| allocated_size(&path, m));
+		Ok(ListedEntry { path, name, metadata, size })
+	}).collect())
+}
+
+#[derive(Default)]
+struct State {
+	stack: Vec<PathBuf>,					// queued directories, the next one to list on top
+	queued: HashSet<PathBuf>,				// what in stack hasn't been claimed by the walk in the meantime
+	running: HashSet<PathBuf>,
+	skipped: HashSet<PathBuf>,				// running when the walk skipped them, dropped once done
+	done: HashMap<PathBuf, Listing>,
+	shutdown: bool,
+}
+
+struct Shared {
+	state: Mutex<State>,
+	work: Condvar,		// something was queued or shutdown
+	ready: Condvar,		// a listing finished
+}
+
+/// Lists directories for the walk, reading ahead on worker threads when there are any.
+pub struct Walker {
+	shared: Option<Arc<Shared>>,
+	workers: Vec<JoinHandle<()>>,
+}
+
+impl Walker {
+	/// A walker using `threads` threads including the caller's, 0 for one per CPU. 1 lists everything on the caller's thread.
+	pub fn new(threads: usize) -> Walker {
+		let threads = if threads == 0 { thread::available_parallelism().map_or(1, |n| n.get()) } else { threads };
+		if threads <= 1 { return Walker { shared: None, workers: Vec::new() }; }
+
+		let shared = Arc::new(Shared { state: Mutex::new(State::default()), work: Condvar::new(), ready: Condvar::new() });
+		let workers = (1..threads).map(|_| {
+			let shared = shared.clone();
+			thread::spawn(move || worker(&shared))
+		}).collect();
+		Walker { shared: Some(shared), workers }
+	}
+
+	/// Queues directories the walk is about to visit, in the order it will visit them.
+	pub fn prefetch(&self, dirs: Vec<PathBuf>) {
+		let Some(shared) = &self.shared else { return };
+		if dirs.is_empty() { return; }
+		let mut state = shared.state.lock().unwrap();
+		for dir in dirs.into_iter().rev() {
+			if state.queued.insert(dir.clone()) { state.stack.push(dir); }
+		}
+		shared.work.notify_all();
+	}
+
+	/// The listing of `path`, from a worker if one has it or is on it, otherwise read now.
+	pub fn list(&self, path: &Path) -> Listing {
+		let Some(shared) = &self.shared else { return list_dir(path) };
+		let mut state = shared.state.lock().unwrap();
+		loop {
+			if let Some(listing) = state.done.remove(path) { return listing; }
+			if !state.running.contains(path) { break; }
+			state = shared.ready.wait(state).unwrap();
+		}
+		// Claim it so no worker starts on it, its stack slot is skipped when popped
+		state.queued.remove(path);
+		drop(state);
+		list_dir(path)
+	}
+
+	/// Tells the walker a prefetched directory won't be listed after all.
+	pub fn skip(&self, path: &Path) {
+		let Some(shared) = &self.shared else { return };
+		let mut state = shared.state.lock().unwrap();
+		state.queued.remove(path);
+		if state.done.remove(path).is_none() && state.running.contains(path) { state.skipped.insert(path.to_path_buf()); }
+	}
+}
+
+fn worker(shared: &Shared) {
+	loop {
+		let path = {
+			let mut state = shared.state.lock().unwrap();
+			loop {
+				if state.shutdown { return; }
+				match state.stack.pop() {
+					Some(p) => if state.queued.remove(&p) { state.running.insert(p.clone()); break p; },
+					None => state = shared.work.wait(state).unwrap(),
+				}
+			}
+		};
+
+		let listing = list_dir(&path);
+
+		let mut state = shared.state.lock().unwrap();
+		state.running.remove(&path);
+		if !state.skipped.remove(&path) { state.done.insert(path, listing); }
+		shared.ready.notify_all();
+	}
+}
+
+impl Drop for Walker {
+	fn drop(&mut self) {
+		if let Some(shared) = &self.shared {
+			shared.state.lock().unwrap().shutdown = true;
+			shared.work.notify_all();
+		}
+		for worker in self.workers.drain(..) { let _ = worker.join(); }
+	}
+}
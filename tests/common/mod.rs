@@ -0,0 +1,54 @@
+#![allow(dead_code)]
+
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+
+
+/// A fresh directory under the system temp dir, removed when dropped.
+pub struct TempDir(pub PathBuf);
+
+impl TempDir {
+	pub fn new(name: &str) -> TempDir {
+		let dir = std::env::temp_dir().join(format!("treee-test-{}-{}", name, std::process::id()));
+		let _ = fs::remove_dir_all(&dir);
+		fs::create_dir_all(&dir).unwrap();
+		TempDir(dir)
+	}
+
+	/// Writes `file` (a path relative to the directory), creating its parents.
+	pub fn write(&self, file: &str, contents: &str) {
+		let path = self.0.join(file);
+		fs::create_dir_all(path.parent().unwrap()).unwrap();
+		fs::write(path, contents).unwrap();
+	}
+
+	pub fn path(&self) -> &str { self.0.to_str().unwrap() }
+}
+
+impl Drop for TempDir {
+	fn drop(&mut self) {
+		// Directories a test locked have to be opened again to be removed
+		#[cfg(unix)]
+		if let Ok(entries) = fs::read_dir(&self.0) {
+			use std::os::unix::fs::PermissionsExt;
+			for entry in entries.flatten() {
+				let _ = fs::set_permissions(entry.path(), fs::Permissions::from_mode(0o755));
+			}
+		}
+		let _ = fs::remove_dir_all(&self.0);
+	}
+}
+
+/// Runs the binary with `args`.
+pub fn t(args: &[&str]) -> Output {
+	Command::new(env!("CARGO_BIN_EXE_t")).args(args).output().unwrap()
+}
+
+/// The printed lines of a successful run.
+pub fn lines(args: &[&str]) -> Vec<String> {
+	let out = t(args);
+	assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+	String::from_utf8_lossy(&out.stdout).lines().map(|l| l.to_string()).collect()
+}
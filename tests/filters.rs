@@ -0,0 +1,65 @@
+mod common;
+
+use common::{TempDir, lines};
+
+
+
+// src/main.rs, src/lib.rs, docs/guide.md, docs/src/notes.txt and an empty assets/
+fn project(name: &str) -> TempDir {
+	let tmp = TempDir::new(name);
+	tmp.write("src/main.rs", "fn main() {}");
+	tmp.write("src/lib.rs", "");
+	tmp.write("docs/guide.md", "# guide");
+	tmp.write("docs/src/notes.txt", "notes");
+	std::fs::create_dir(tmp.0.join("assets")).unwrap();
+	tmp
+}
+
+#[test]
+fn where_never_widens_file_matches() {
+	let tmp = project("where-files");
+	let alone = lines(&[tmp.path(), "-f", "rs$", "-S"]);
+	assert!(alone.iter().any(|l| l == "Matched 1 directories, 2 files"), "{:?}", alone);
+
+	// An expression that holds for everything changes nothing
+	assert_eq!(lines(&[tmp.path(), "-f", "rs$", "-S", "--where", "NOT name ~ zzz"]), alone);
+
+	// One that doesn't only takes entries away
+	let narrowed = lines(&[tmp.path(), "-f", "rs$", "-S", "--where", "name = main.rs"]);
+	assert!(narrowed.iter().all(|l| alone.contains(l) || l.starts_with("Matched")), "{:?}", narrowed);
+	assert!(narrowed.iter().any(|l| l == "Matched 1 directories, 1 files"), "{:?}", narrowed);
+}
+
+#[test]
+fn where_never_widens_dir_matches() {
+	let tmp = project("where-dirs");
+	let alone = lines(&[tmp.path(), "-d", "^src$", "-S"]);
+	assert!(alone.iter().any(|l| l == "Matched 3 directories, 0 files"), "{:?}", alone);
+	assert_eq!(lines(&[tmp.path(), "-d", "^src$", "-S", "--where", "NOT name ~ zzz"]), alone);
+}
+
+#[test]
+fn where_alone_filters() {
+	let tmp = project("where-alone");
+	let out = lines(&[tmp.path(), "--where", "ext is md"]);
+	assert_eq!(out.len(), 2, "{:?}", out);
+	assert!(out[0].ends_with("docs "), "{:?}", out);
+	assert!(out[1].ends_with("guide.md "), "{:?}", out);
+}
+
+#[test]
+fn exclusions_keep_the_meaning_of_escapes() {
+	let tmp = TempDir::new("exclude-escapes");
+	for name in ["file10", "file2", "notes", "README.TMP"] { tmp.write(name, ""); }
+	tmp.write("Build/out.o", "");
+
+	// \D is any non digit, lowercasing the pattern would turn it into \d
+	let out = lines(&[tmp.path(), "--exclude-file", r"^\D+$"]);
+	assert!(out.iter().any(|l| l.contains("file10")) && out.iter().any(|l| l.contains("file2")), "{:?}", out);
+	assert!(!out.iter().any(|l| l.contains("notes")), "{:?}", out);
+
+	// Still case insensitive
+	let out = lines(&[tmp.path(), "--exclude-file", r"\.tmp$", "--exclude-dir", "^build$"]);
+	assert!(!out.iter().any(|l| l.contains("README.TMP") || l.contains("Build")), "{:?}", out);
+	assert!(out.iter().any(|l| l.contains("notes")), "{:?}", out);
+}
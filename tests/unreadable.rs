@@ -0,0 +1,73 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+
+use common::{TempDir, t};
+
+
+
+// open/file is readable, locked/ can't be listed
+fn tree_with_locked_dir(name: &str) -> Option<TempDir> {
+	let tmp = TempDir::new(name);
+	fs::create_dir_all(tmp.0.join("open")).unwrap();
+	fs::write(tmp.0.join("open").join("file"), "contents").unwrap();
+	fs::create_dir(tmp.0.join("locked")).unwrap();
+	fs::write(tmp.0.join("locked").join("hidden"), "contents").unwrap();
+	fs::set_permissions(tmp.0.join("locked"), fs::Permissions::from_mode(0o000)).unwrap();
+
+	// Permissions don't stop root, there's nothing to test then
+	if fs::read_dir(tmp.0.join("locked")).is_ok() {
+		eprintln!("skipped: running with permissions that ignore chmod 000");
+		return None;
+	}
+	Some(tmp)
+}
+
+#[test]
+fn unreadable_dir_is_shown_inline_and_counted() {
+	let Some(tmp) = tree_with_locked_dir("inline") else { return };
+	let out = t(&[tmp.path(), "-S"]);
+	assert!(out.status.success());
+
+	let stdout = String::from_utf8_lossy(&out.stdout);
+	assert!(stdout.lines().any(|l| l.contains("locked") && l.ends_with("[permission denied]")), "{}", stdout);
+	assert!(stdout.lines().any(|l| l.contains("file")), "{}", stdout);
+	assert!(!stdout.contains("hidden"), "{}", stdout);
+	assert!(stdout.lines().any(|l| l == "1 entry could not be read"), "{}", stdout);
+}
+
+#[test]
+fn unreadable_dir_is_shown_when_streaming() {
+	let Some(tmp) = tree_with_locked_dir("stream") else { return };
+	let out = t(&[tmp.path(), "-S", "--stream"]);
+	assert!(out.status.success());
+
+	let stdout = String::from_utf8_lossy(&out.stdout);
+	assert_eq!(stdout.lines().filter(|l| l.starts_with("locked/")).count(), 1, "{}", stdout);
+	assert!(stdout.lines().any(|l| l == "locked/ [permission denied]"), "{}", stdout);
+	assert!(stdout.lines().any(|l| l == "1 entry could not be read"), "{}", stdout);
+}
+
+#[test]
+fn strict_fails_after_printing() {
+	let Some(tmp) = tree_with_locked_dir("strict") else { return };
+	let out = t(&[tmp.path(), "--strict"]);
+	assert!(!out.status.success());
+
+	// The tree is still printed, the error comes after it
+	let stdout = String::from_utf8_lossy(&out.stdout);
+	assert!(stdout.contains("[permission denied]"), "{}", stdout);
+	assert!(String::from_utf8_lossy(&out.stderr).contains("1 entry could not be read"));
+}
+
+#[test]
+fn strict_succeeds_when_everything_is_readable() {
+	let tmp = TempDir::new("readable");
+	fs::write(tmp.0.join("file"), "contents").unwrap();
+	let out = t(&[tmp.path(), "--strict", "-S"]);
+	assert!(out.status.success());
+	assert!(!String::from_utf8_lossy(&out.stdout).contains("could not be read"));
+}